@@ -21,4 +21,32 @@ export function timeSince(initial_time) {
     }
 
     return res;
-}
\ No newline at end of file
+}
+
+// Only the usernames in `mentions`, the users the server resolved, become links.
+export function splitMentions(text, mentions = []) {
+    const parts = [];
+    const pattern = /(^|[^\p{L}\p{N}_-])@([\p{L}\p{N}_-]+)/gu;
+
+    let last = 0;
+    for (const match of text.matchAll(pattern)) {
+        if (!mentions.includes(match[2])) {
+            continue;
+        }
+
+        const start = match.index + match[1].length;
+
+        if (start > last) {
+            parts.push({ text: text.slice(last, start) });
+        }
+
+        parts.push({ text: `@${match[2]}`, username: match[2] });
+        last = start + match[2].length + 1;
+    }
+
+    if (last < text.length) {
+        parts.push({ text: text.slice(last) });
+    }
+
+    return parts;
+}
@@ -0,0 +1,4 @@
+create index if not exists comments_thread_id_idx on comments(thread_id);
+create index if not exists comments_pid_idx on comments(pid);
+create index if not exists threads_user_id_idx on threads(user_id);
+create index if not exists threads_created_at_idx on threads(created_at);
@@ -0,0 +1,19 @@
+create table if not exists mentions (
+    id              bigserial primary key,
+    user_id         bigint not null references users(id),
+    author_user_id  bigint not null references users(id),
+    thread_id       bigint not null references threads(id),
+    comment_id      bigint references comments(id) default null,
+    created_at      timestamptz not null default now()
+);
+
+create table if not exists notifications (
+    id              bigserial primary key,
+    user_id         bigint not null references users(id),
+    actor_user_id   bigint not null references users(id),
+    kind            text not null,
+    thread_id       bigint references threads(id) default null,
+    comment_id      bigint references comments(id) default null,
+    is_read         boolean not null default false,
+    created_at      timestamptz not null default now()
+);
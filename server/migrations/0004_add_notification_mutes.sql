@@ -0,0 +1,6 @@
+create table if not exists notification_mutes (
+    user_id     bigint not null references users(id),
+    kind        text not null,
+    created_at  timestamptz not null default now(),
+    primary key (user_id, kind)
+);
@@ -0,0 +1,8 @@
+alter table users add column if not exists display_name text;
+alter table users add column if not exists bio text not null default '';
+alter table users add column if not exists bio_html text not null default '';
+alter table users add column if not exists links text[] not null default '{}';
+alter table users add column if not exists location text;
+alter table users add column if not exists pronouns text;
+alter table users add column if not exists avatar_url text;
+alter table users add column if not exists avatar_thumbnail_url text;
@@ -0,0 +1,22 @@
+alter table users add column if not exists role text not null default 'user'
+    check (role in ('user', 'moderator', 'admin'));
+
+alter table threads add column if not exists removed_at timestamptz;
+alter table comments add column if not exists removed_at timestamptz;
+
+create table if not exists reports (
+    id                  bigserial primary key,
+    reporter_user_id    bigint not null references users(id),
+    target_kind         text not null,
+    target_id           bigint not null,
+    reason              text not null,
+    note                text,
+    status              text not null default 'open',
+    resolved_by_user_id bigint references users(id) default null,
+    resolved_at         timestamptz,
+    created_at          timestamptz not null default now()
+);
+
+create unique index if not exists reports_open_key
+    on reports(reporter_user_id, target_kind, target_id)
+    where status = 'open';
@@ -0,0 +1,22 @@
+create table if not exists bans (
+    id                  bigserial primary key,
+    user_id             bigint not null references users(id),
+    kind                text not null check (kind in ('suspension', 'ban', 'shadowban')),
+    reason              text not null,
+    expires_at          timestamptz,
+    created_by_user_id  bigint not null references users(id),
+    created_at          timestamptz not null default now(),
+    lifted_at           timestamptz,
+    lifted_by_user_id   bigint references users(id) default null,
+    appeal              text,
+    appealed_at         timestamptz
+);
+
+create or replace view active_bans as
+    select *
+    from bans
+    where lifted_at is null
+        and (expires_at is null or expires_at > now());
+
+alter table threads add column if not exists shadowed boolean not null default false;
+alter table comments add column if not exists shadowed boolean not null default false;
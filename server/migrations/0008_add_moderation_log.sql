@@ -0,0 +1,27 @@
+alter table threads add column if not exists locked_at timestamptz;
+alter table threads add column if not exists pinned_at timestamptz;
+alter table threads add column if not exists edited_at timestamptz;
+alter table comments add column if not exists edited_at timestamptz;
+
+create table if not exists mod_log (
+    id              bigserial primary key,
+    actor_user_id   bigint not null references users(id),
+    action          text not null,
+    target_kind     text not null,
+    target_id       bigint not null,
+    target_user_id  bigint references users(id) default null,
+    reason          text,
+    before          jsonb,
+    after           jsonb,
+    created_at      timestamptz not null default now()
+);
+
+create or replace function mod_log_append_only() returns trigger as $$
+begin
+    raise exception 'mod_log is append-only';
+end;
+$$ language plpgsql;
+
+create or replace trigger mod_log_append_only
+    before update or delete on mod_log
+    for each statement execute function mod_log_append_only();
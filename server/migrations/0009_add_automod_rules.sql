@@ -0,0 +1,12 @@
+alter table threads add column if not exists held_at timestamptz;
+alter table comments add column if not exists held_at timestamptz;
+
+-- Reports filed by the automoderator have no reporter.
+alter table reports alter column reporter_user_id drop not null;
+
+create table if not exists automod_rules (
+    id                  bigserial primary key,
+    rule                jsonb not null,
+    created_by_user_id  bigint not null references users(id),
+    created_at          timestamptz not null default now()
+);
@@ -0,0 +1,14 @@
+create table if not exists blocks (
+    blocker_user_id     bigint not null references users(id),
+    blocked_user_id     bigint not null references users(id),
+    created_at          timestamptz not null default now(),
+    primary key (blocker_user_id, blocked_user_id),
+    constraint user_cannot_block_self check (blocker_user_id <> blocked_user_id)
+);
+
+create table if not exists thread_mutes (
+    user_id     bigint not null references users(id),
+    thread_id   bigint not null references threads(id),
+    created_at  timestamptz not null default now(),
+    primary key (user_id, thread_id)
+);
@@ -0,0 +1,24 @@
+alter table users add column if not exists dms_from_following_only boolean not null default false;
+
+-- One conversation per pair of users, stored with the lower user id first.
+create table if not exists conversations (
+    id                  bigserial primary key,
+    user_a_id           bigint not null references users(id),
+    user_b_id           bigint not null references users(id),
+    created_at          timestamptz not null default now(),
+    last_message_at     timestamptz not null default now(),
+    unique (user_a_id, user_b_id),
+    check (user_a_id < user_b_id)
+);
+
+create table if not exists messages (
+    id                  bigserial primary key,
+    conversation_id     bigint not null references conversations(id),
+    sender_user_id      bigint not null references users(id),
+    content             text not null,
+    created_at          timestamptz not null default now(),
+    read_at             timestamptz,
+    removed_at          timestamptz
+);
+
+create index if not exists messages_conversation_id_idx on messages(conversation_id, created_at);
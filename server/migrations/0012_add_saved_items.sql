@@ -0,0 +1,13 @@
+create table if not exists thread_saves (
+    thread_id   bigint not null references threads(id),
+    user_id     bigint not null references users(id),
+    created_at  timestamptz not null default now(),
+    primary key (thread_id, user_id)
+);
+
+create table if not exists comment_saves (
+    comment_id  bigint not null references comments(id),
+    user_id     bigint not null references users(id),
+    created_at  timestamptz not null default now(),
+    primary key (comment_id, user_id)
+);
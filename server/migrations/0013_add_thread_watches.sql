@@ -0,0 +1,11 @@
+alter table users add column if not exists auto_watch boolean not null default true;
+
+create table if not exists thread_watches (
+    user_id         bigint not null references users(id),
+    thread_id       bigint not null references threads(id),
+    created_at      timestamptz not null default now(),
+    last_read_at    timestamptz not null default now(),
+    primary key (user_id, thread_id)
+);
+
+create index if not exists thread_watches_thread_id_idx on thread_watches(thread_id);
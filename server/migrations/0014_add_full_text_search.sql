@@ -0,0 +1,17 @@
+alter table threads add column if not exists search tsvector
+    generated always as (
+        setweight(to_tsvector('english', title), 'A') || setweight(to_tsvector('english', content), 'B')
+    ) stored;
+
+alter table comments add column if not exists search tsvector
+    generated always as (to_tsvector('english', content)) stored;
+
+create index if not exists threads_search_idx on threads using gin(search);
+create index if not exists comments_search_idx on comments using gin(search);
+
+-- Search snippets are returned as HTML, so the text they're cut from is escaped first.
+create or replace function html_escape(text) returns text
+language sql immutable
+as $$
+    select replace(replace(replace($1, '&', '&amp;'), '<', '&lt;'), '>', '&gt;')
+$$;
@@ -0,0 +1,4 @@
+create extension if not exists pg_trgm;
+
+create index if not exists users_username_prefix_idx on users(lower(username) text_pattern_ops);
+create index if not exists threads_title_trgm_idx on threads using gin(lower(title) gin_trgm_ops);
@@ -0,0 +1,2 @@
+create index if not exists mentions_thread_id_idx on mentions(thread_id) where comment_id is null;
+create index if not exists mentions_comment_id_idx on mentions(comment_id);
@@ -0,0 +1,7 @@
+-- Inboxes, saved items and watched threads are all listed per user, newest first.
+create index if not exists notifications_user_id_created_at_idx on notifications(user_id, created_at desc);
+create index if not exists notifications_unread_user_id_idx on notifications(user_id) where not is_read;
+create index if not exists mentions_user_id_idx on mentions(user_id);
+create index if not exists thread_saves_user_id_created_at_idx on thread_saves(user_id, created_at desc);
+create index if not exists comment_saves_user_id_created_at_idx on comment_saves(user_id, created_at desc);
+create index if not exists thread_watches_user_id_created_at_idx on thread_watches(user_id, created_at desc);
@@ -0,0 +1,684 @@
+//! Operational tasks run by the `forum-admin` binary.
+
+use crate::auth::Role;
+use crate::automod::PostKind;
+use crate::error::Error;
+use crate::mod_log::{self, ModAction, ModLogEntry};
+use crate::routes::bans::MAX_DURATION_HOURS;
+use crate::routes::users::{hash_password, validate_new_user};
+use anyhow::{bail, Context, Result};
+use chrono::{Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+
+#[derive(Debug)]
+pub struct SpamRemoved {
+    pub threads: usize,
+    pub comments: usize,
+}
+
+#[derive(Debug)]
+pub struct CountersRecomputed {
+    pub conversations: u64,
+}
+
+pub async fn create_user(db: &PgPool, username: &str, password: &str, role: Role) -> Result<i64> {
+    validate_new_user(username, password).map_err(invalid)?;
+
+    let password_hash = hash_password(password.to_string()).await?;
+
+    let id = sqlx::query_scalar!(
+        "
+            insert into users(username, password_hash, role)
+            values($1, $2, $3)
+            on conflict (username) do nothing
+            returning id
+        ",
+        username,
+        password_hash,
+        role.as_str()
+    )
+    .fetch_optional(db)
+    .await?
+    .with_context(|| format!("username {:?} is already taken", username))?;
+
+    Ok(id)
+}
+
+/// Changes a user's role, returning the role they had before.
+pub async fn set_role(
+    db: &PgPool,
+    actor: &str,
+    username: &str,
+    role: Role,
+    reason: Option<&str>,
+) -> Result<Role> {
+    let mut tx = db.begin().await?;
+
+    let actor_id = user_id(&mut tx, actor).await?;
+    let user_id = user_id(&mut tx, username).await?;
+
+    let before = sqlx::query_scalar!(
+        "
+            select role
+            from users
+            where id = $1
+            for update
+        ",
+        user_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            update users
+            set role = $2
+            where id = $1
+        ",
+        user_id,
+        role.as_str()
+    )
+    .execute(&mut tx)
+    .await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: actor_id,
+            action: ModAction::ChangeRole,
+            target_kind: "user",
+            target_id: user_id,
+            target_user_id: Some(user_id),
+            reason,
+            before: Some(serde_json::json!({ "role": before })),
+            after: Some(serde_json::json!({ "role": role.as_str() })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Role::from_db(&before))
+}
+
+/// Bans, suspends or shadowbans a user, returning the ban's id. Suspensions
+/// need a duration, bans can't have one and shadowbans may.
+pub async fn ban(
+    db: &PgPool,
+    actor: &str,
+    username: &str,
+    kind: &str,
+    reason: &str,
+    duration_hours: Option<i64>,
+) -> Result<i64> {
+    let expires_at = match (kind, duration_hours) {
+        (_, Some(hours)) if hours <= 0 => bail!("the duration must be positive"),
+        (_, Some(hours)) if hours > MAX_DURATION_HOURS => {
+            bail!("the duration can be at most {} hours", MAX_DURATION_HOURS)
+        }
+        ("ban", Some(_)) => bail!("a permanent ban can't have a duration"),
+        ("suspension", None) => bail!("a suspension needs a duration"),
+        (_, Some(hours)) => Some(Utc::now() + Duration::hours(hours)),
+        (_, None) => None,
+    };
+
+    let mut tx = db.begin().await?;
+
+    let actor_id = user_id(&mut tx, actor).await?;
+    let user_id = user_id(&mut tx, username).await?;
+
+    let ban_id = insert_ban(&mut tx, actor_id, user_id, kind, reason, expires_at).await?;
+
+    tx.commit().await?;
+
+    Ok(ban_id)
+}
+
+pub async fn reset_password(db: &PgPool, username: &str, password: &str) -> Result<()> {
+    let password_hash = hash_password(password.to_string()).await?;
+
+    sqlx::query!(
+        "
+            update users
+            set password_hash = $2
+            where username = $1
+            returning id
+        ",
+        username,
+        password_hash
+    )
+    .fetch_optional(db)
+    .await?
+    .with_context(|| format!("no user named {:?}", username))?;
+
+    Ok(())
+}
+
+/// Moves everything `from` posted, voted on, saved, watched, muted, followed
+/// and blocked over to `into`, then bans `from`. Their messages, reports and
+/// moderation history stay with the old account.
+pub async fn merge_users(db: &PgPool, actor: &str, from: &str, into: &str) -> Result<()> {
+    let mut tx = db.begin().await?;
+
+    let actor_id = user_id(&mut tx, actor).await?;
+    let from_id = user_id(&mut tx, from).await?;
+    let into_id = user_id(&mut tx, into).await?;
+
+    if from_id == into_id {
+        bail!("can't merge an account into itself");
+    }
+
+    sqlx::query!(
+        "update threads set user_id = $2 where user_id = $1",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "update comments set user_id = $2 where user_id = $1",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "update mentions set user_id = $2 where user_id = $1",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "update mentions set author_user_id = $2 where author_user_id = $1",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "update notifications set user_id = $2 where user_id = $1",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "update notifications set actor_user_id = $2 where actor_user_id = $1",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    // The rest are keyed on the user, so rows `into` already has are dropped.
+    sqlx::query!(
+        "
+            insert into thread_votes(thread_id, user_id, created_at)
+            select thread_id, $2, created_at
+            from thread_votes
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into comment_votes(comment_id, user_id, created_at)
+            select comment_id, $2, created_at
+            from comment_votes
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into thread_saves(thread_id, user_id, created_at)
+            select thread_id, $2, created_at
+            from thread_saves
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into comment_saves(comment_id, user_id, created_at)
+            select comment_id, $2, created_at
+            from comment_saves
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into thread_watches(user_id, thread_id, created_at, last_read_at)
+            select $2, thread_id, created_at, last_read_at
+            from thread_watches
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into thread_mutes(user_id, thread_id, created_at)
+            select $2, thread_id, created_at
+            from thread_mutes
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into notification_mutes(user_id, kind, created_at)
+            select $2, kind, created_at
+            from notification_mutes
+            where user_id = $1
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into follows(followee_user_id, follower_user_id, created_at)
+            select $2, follower_user_id, created_at
+            from follows
+            where followee_user_id = $1
+                and follower_user_id <> $2
+            union all
+            select followee_user_id, $2, created_at
+            from follows
+            where follower_user_id = $1
+                and followee_user_id <> $2
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into blocks(blocker_user_id, blocked_user_id, created_at)
+            select $2, blocked_user_id, created_at
+            from blocks
+            where blocker_user_id = $1
+                and blocked_user_id <> $2
+            union all
+            select blocker_user_id, $2, created_at
+            from blocks
+            where blocked_user_id = $1
+                and blocker_user_id <> $2
+            on conflict do nothing
+        ",
+        from_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from thread_votes where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from comment_votes where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from thread_saves where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from comment_saves where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from thread_watches where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from thread_mutes where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from notification_mutes where user_id = $1",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from follows where $1 in (followee_user_id, follower_user_id)",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from blocks where $1 in (blocker_user_id, blocked_user_id)",
+        from_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: actor_id,
+            action: ModAction::MergeUsers,
+            target_kind: "user",
+            target_id: from_id,
+            target_user_id: Some(from_id),
+            reason: None,
+            before: Some(serde_json::json!({ "username": from })),
+            after: Some(serde_json::json!({ "merged_into": into })),
+        },
+    )
+    .await?;
+
+    insert_ban(
+        &mut tx,
+        actor_id,
+        from_id,
+        "ban",
+        &format!("Merged into {}", into),
+        None,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Removes every thread and comment a user posted that's still up, resolving
+/// any open reports on them.
+pub async fn delete_spam(db: &PgPool, actor: &str, username: &str) -> Result<SpamRemoved> {
+    let mut tx = db.begin().await?;
+
+    let actor_id = user_id(&mut tx, actor).await?;
+    let user_id = user_id(&mut tx, username).await?;
+
+    let thread_ids = sqlx::query_scalar!(
+        "
+            select id
+            from threads
+            where user_id = $1
+                and removed_at is null
+        ",
+        user_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for &id in &thread_ids {
+        remove(&mut tx, actor_id, PostKind::Thread, id).await?;
+    }
+
+    let comment_ids = sqlx::query_scalar!(
+        "
+            select id
+            from comments
+            where user_id = $1
+                and removed_at is null
+        ",
+        user_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for &id in &comment_ids {
+        remove(&mut tx, actor_id, PostKind::Comment, id).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(SpamRemoved {
+        threads: thread_ids.len(),
+        comments: comment_ids.len(),
+    })
+}
+
+/// Recomputes the counters that are stored rather than counted on read, which
+/// is each conversation's `last_message_at`. Karma is always counted on read
+/// from the votes a user's posts have received.
+pub async fn recompute_counters(db: &PgPool) -> Result<CountersRecomputed> {
+    let mut tx = db.begin().await?;
+
+    let conversations = sqlx::query!(
+        "
+            update conversations a
+            set last_message_at = b.last_message_at
+            from (
+                select conversation_id, max(created_at) as last_message_at
+                from messages
+                group by conversation_id
+            ) b
+            where a.id = b.conversation_id
+                and a.last_message_at is distinct from b.last_message_at
+        "
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(CountersRecomputed { conversations })
+}
+
+/// Spells out a validation error the way the API's response lists it.
+fn invalid(e: Error) -> anyhow::Error {
+    match e {
+        Error::UnprocessableEntity { errors } => {
+            let errors = errors
+                .into_iter()
+                .map(|(field, messages)| format!("{} {}", field, messages.join(", ")))
+                .collect::<Vec<_>>();
+
+            anyhow::anyhow!("invalid {}", errors.join("; "))
+        }
+        e => e.into(),
+    }
+}
+
+async fn user_id(tx: &mut Transaction<'_, Postgres>, username: &str) -> Result<i64> {
+    sqlx::query_scalar!(
+        "
+            select id
+            from users
+            where username = $1
+        ",
+        username
+    )
+    .fetch_optional(tx)
+    .await?
+    .with_context(|| format!("no user named {:?}", username))
+}
+
+async fn insert_ban(
+    tx: &mut Transaction<'_, Postgres>,
+    actor_id: i64,
+    user_id: i64,
+    kind: &str,
+    reason: &str,
+    expires_at: Option<chrono::DateTime<Utc>>,
+) -> Result<i64> {
+    let ban_id = sqlx::query_scalar!(
+        "
+            insert into bans(user_id, kind, reason, expires_at, created_by_user_id)
+            values($1, $2, $3, $4, $5)
+            returning id
+        ",
+        user_id,
+        kind,
+        reason,
+        expires_at,
+        actor_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    mod_log::record(
+        tx,
+        ModLogEntry {
+            actor_user_id: actor_id,
+            action: ModAction::Ban,
+            target_kind: "user",
+            target_id: user_id,
+            target_user_id: Some(user_id),
+            reason: Some(reason),
+            before: None,
+            after: Some(serde_json::json!({
+                "ban_id": ban_id,
+                "kind": kind,
+                "expires_at": expires_at,
+            })),
+        },
+    )
+    .await?;
+
+    Ok(ban_id)
+}
+
+/// Removes a thread or comment as spam, logging it like a removal from the
+/// mod queue.
+async fn remove(
+    tx: &mut Transaction<'_, Postgres>,
+    actor_id: i64,
+    kind: PostKind,
+    id: i64,
+) -> Result<()> {
+    let (action, before) = match kind {
+        PostKind::Thread => {
+            let before = mod_log::thread_snapshot(tx, id).await?;
+
+            sqlx::query!(
+                "
+                    update threads
+                    set removed_at = now()
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            (ModAction::RemoveThread, before)
+        }
+        PostKind::Comment => {
+            let before = mod_log::comment_snapshot(tx, id).await?;
+
+            sqlx::query!(
+                "
+                    update comments
+                    set removed_at = now()
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            (ModAction::RemoveComment, before)
+        }
+    };
+
+    let after = match kind {
+        PostKind::Thread => mod_log::thread_snapshot(tx, id).await?,
+        PostKind::Comment => mod_log::comment_snapshot(tx, id).await?,
+    };
+
+    let (before, after) = before.zip(after).context("post disappeared while removing it")?;
+
+    sqlx::query!(
+        "
+            update reports
+            set status = 'resolved',
+                resolved_by_user_id = $1,
+                resolved_at = now()
+            where target_kind = $2
+                and target_id = $3
+                and status = 'open'
+        ",
+        actor_id,
+        kind.as_str(),
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    mod_log::record(
+        tx,
+        ModLogEntry {
+            actor_user_id: actor_id,
+            action,
+            target_kind: kind.as_str(),
+            target_id: id,
+            target_user_id: Some(before.user_id),
+            reason: Some("spam"),
+            before: Some(before.value),
+            after: Some(after.value),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
@@ -16,6 +16,45 @@ pub struct AuthUser {
 
 pub struct MaybeAuthUser(pub Option<AuthUser>);
 
+/// An authenticated user, without the ban check `AuthUser` does on writes.
+///
+/// Only for the few actions still open to banned users, like appealing.
+pub struct AnyAuthUser {
+    pub id: i64,
+}
+
+/// An authenticated user with the moderator or admin role.
+pub struct ModUser {
+    pub id: i64,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Moderator => "moderator",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn from_db(role: &str) -> Self {
+        match role {
+            "admin" => Self::Admin,
+            "moderator" => Self::Moderator,
+            _ => Self::User,
+        }
+    }
+}
+
 impl MaybeAuthUser {
     pub fn id(&self) -> Option<i64> {
         self.0.as_ref().map(|auth_user| auth_user.id)
@@ -43,14 +82,17 @@ impl AuthUser {
         .expect("JWT encode failed")
     }
 
-    fn from_authorization(state: &AppState, auth_header: &HeaderValue) -> Result<Self, Error> {
+    pub(crate) fn from_authorization(
+        state: &AppState,
+        auth_header: &HeaderValue,
+    ) -> Result<Self, Error> {
         let auth_header = auth_header.to_str().map_err(|_e| {
-            log::debug!("Authorization header is not UTF-8");
+            tracing::debug!("Authorization header is not UTF-8");
             Error::Unauthorized
         })?;
 
         if !auth_header.starts_with(SCHEME_PREFIX) {
-            log::debug!(
+            tracing::debug!(
                 "Authorization header is using the wrong scheme: {:?}",
                 auth_header
             );
@@ -59,13 +101,14 @@ impl AuthUser {
 
         let token = &auth_header[SCHEME_PREFIX.len()..];
 
+        #[allow(clippy::needless_borrow)]
         let jwt = decode::<AuthUserClaims>(
             &token,
             &DecodingKey::from_secret(state.key.as_ref()),
             &Validation::new(jsonwebtoken::Algorithm::HS256),
         )
         .map_err(|e| {
-            log::debug!(
+            tracing::debug!(
                 "Failed to parse and verify Authorization header {:?}: {}",
                 auth_header,
                 e
@@ -76,12 +119,53 @@ impl AuthUser {
         let claims = jwt.claims;
 
         if claims.exp < OffsetDateTime::now_utc().unix_timestamp() {
-            log::debug!("Token expired");
+            tracing::debug!("Token expired");
             return Err(Error::Unauthorized);
         }
 
         Ok(Self { id: claims.id })
     }
+
+    pub(crate) async fn role(&self, state: &AppState) -> Result<Role, Error> {
+        sqlx::query_scalar!(
+            "
+                select role
+                from users
+                where id = $1
+            ",
+            self.id
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .map(|role| Role::from_db(&role))
+        .ok_or(Error::Unauthorized)
+    }
+
+    async fn check_not_banned(&self, state: &AppState) -> Result<(), Error> {
+        let ban = sqlx::query!(
+            r#"
+                select
+                    reason as "reason!",
+                    expires_at
+                from active_bans
+                where user_id = $1
+                    and kind <> 'shadowban'
+                order by expires_at desc nulls first
+                limit 1
+            "#,
+            self.id
+        )
+        .fetch_optional(&state.db)
+        .await?;
+
+        match ban {
+            Some(ban) => Err(Error::Banned {
+                reason: ban.reason,
+                expires_at: ban.expires_at,
+            }),
+            None => Ok(()),
+        }
+    }
 }
 
 #[async_trait]
@@ -99,7 +183,35 @@ where
             .get(AUTHORIZATION)
             .ok_or(Error::Unauthorized)?;
 
-        Self::from_authorization(&state, auth_header)
+        let auth_user = Self::from_authorization(&state, auth_header)?;
+
+        // Banned and suspended users can still read, but not post, vote or edit.
+        if !parts.method.is_safe() {
+            auth_user.check_not_banned(&state).await?;
+        }
+
+        Ok(auth_user)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AnyAuthUser
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+        let auth_header = parts
+            .headers
+            .get(AUTHORIZATION)
+            .ok_or(Error::Unauthorized)?;
+
+        let auth_user = AuthUser::from_authorization(&state, auth_header)?;
+
+        Ok(Self { id: auth_user.id })
     }
 }
 
@@ -123,3 +235,27 @@ where
         ))
     }
 }
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ModUser
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth_user = AuthUser::from_request_parts(parts, state).await?;
+        let state = AppState::from_ref(state);
+        let role = auth_user.role(&state).await?;
+
+        if role < Role::Moderator {
+            return Err(Error::Forbidden);
+        }
+
+        Ok(Self {
+            id: auth_user.id,
+            role,
+        })
+    }
+}
@@ -0,0 +1,694 @@
+use crate::auth::Role;
+use crate::error::Error;
+use crate::validate::{Validate, Violations};
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostKind {
+    Thread,
+    Comment,
+}
+
+impl PostKind {
+    /// Matches the `target_kind` of reports on the post.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Thread => "thread",
+            Self::Comment => "comment",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Refuses the post with a `422 Unprocessable Entity`.
+    Reject,
+    /// Accepts the post but hides it from everyone but its author until a
+    /// moderator approves it from the mod queue.
+    Hold,
+    /// Accepts the post and reports it to the mod queue.
+    Flag,
+}
+
+/// An automoderator rule.
+///
+/// A rule fires when the post matches any of its keywords, patterns or blocked
+/// domains, and its author is below the minimum account age or karma. Either
+/// half is skipped when the rule doesn't set it, so a rule with only
+/// `min_karma` applies to every post by a low-karma author.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    /// The kinds of posts the rule checks; all of them when empty.
+    #[serde(default)]
+    pub applies_to: Vec<PostKind>,
+    /// Case-insensitive whole words or phrases.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Regular expressions, matched against the title and content.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Domains that can't be linked to, including their subdomains.
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+    pub min_account_age_hours: Option<i64>,
+    pub min_karma: Option<i64>,
+    pub action: RuleAction,
+    /// Shown to the author when the post is rejected, and to moderators.
+    pub message: Option<String>,
+}
+
+impl Rule {
+    fn has_content_conditions(&self) -> bool {
+        !(self.keywords.is_empty() && self.patterns.is_empty() && self.blocked_domains.is_empty())
+    }
+
+    fn has_author_conditions(&self) -> bool {
+        self.min_account_age_hours.is_some() || self.min_karma.is_some()
+    }
+}
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_MESSAGE_LENGTH: usize = 1000;
+/// Bounds each list of conditions, and the length of each entry in it.
+const MAX_CONDITIONS: usize = 500;
+const MAX_CONDITION_LENGTH: usize = 500;
+
+/// The highest minimum account age a rule can set: ten years.
+const MAX_MIN_ACCOUNT_AGE_HOURS: i64 = 10 * 365 * 24;
+
+/// The highest minimum karma a rule can set.
+const MAX_MIN_KARMA: i64 = 1_000_000;
+
+impl Validate for Rule {
+    fn check(&self, violations: &mut Violations) {
+        violations
+            .field("name", &self.name)
+            .not_blank()
+            .max_chars(MAX_NAME_LENGTH);
+
+        for (name, conditions) in [
+            ("keywords", &self.keywords),
+            ("patterns", &self.patterns),
+            ("blocked_domains", &self.blocked_domains),
+        ] {
+            if conditions.len() > MAX_CONDITIONS {
+                violations.add(
+                    name,
+                    format!("must have at most {} entries", MAX_CONDITIONS),
+                );
+            }
+
+            for condition in conditions {
+                violations.field(name, condition).rule(
+                    |condition| condition.chars().count() <= MAX_CONDITION_LENGTH,
+                    format!("must each be at most {} characters", MAX_CONDITION_LENGTH),
+                );
+            }
+        }
+
+        if let Some(message) = &self.message {
+            violations
+                .field("message", message)
+                .max_chars(MAX_MESSAGE_LENGTH);
+        }
+    }
+}
+
+/// A rule with its keywords and patterns compiled.
+pub struct CompiledRule {
+    /// The rule's id in `automod_rules`, or `None` for rules from the rules file.
+    pub id: Option<i64>,
+    pub rule: Rule,
+    keywords: Option<Regex>,
+    patterns: RegexSet,
+    blocked_domains: Vec<String>,
+}
+
+impl CompiledRule {
+    pub fn new(id: Option<i64>, rule: Rule) -> Result<Self, Error> {
+        if rule.name.trim().is_empty() {
+            return Err(Error::unprocessable_entity([("name", "can't be empty")]));
+        }
+
+        if !(rule.has_content_conditions() || rule.has_author_conditions()) {
+            return Err(Error::unprocessable_entity([(
+                "rule",
+                "must have at least one condition",
+            )]));
+        }
+
+        if let Some(hours) = rule.min_account_age_hours {
+            if !(0..=MAX_MIN_ACCOUNT_AGE_HOURS).contains(&hours) {
+                return Err(Error::unprocessable_entity([(
+                    "min_account_age_hours",
+                    format!("must be between 0 and {}", MAX_MIN_ACCOUNT_AGE_HOURS),
+                )]));
+            }
+        }
+
+        if let Some(karma) = rule.min_karma {
+            if !(0..=MAX_MIN_KARMA).contains(&karma) {
+                return Err(Error::unprocessable_entity([(
+                    "min_karma",
+                    format!("must be between 0 and {}", MAX_MIN_KARMA),
+                )]));
+            }
+        }
+
+        let keywords = rule
+            .keywords
+            .iter()
+            .map(|keyword| keyword.trim())
+            .filter(|keyword| !keyword.is_empty())
+            .map(keyword_pattern)
+            .collect::<Vec<_>>();
+
+        // Escaped keywords always parse, but too many can exceed the size limit.
+        let keywords = if keywords.is_empty() {
+            None
+        } else {
+            Some(
+                Regex::new(&format!("(?i){}", keywords.join("|")))
+                    .map_err(|e| Error::unprocessable_entity([("keywords", e.to_string())]))?,
+            )
+        };
+
+        let patterns = RegexSet::new(&rule.patterns)
+            .map_err(|e| Error::unprocessable_entity([("patterns", e.to_string())]))?;
+
+        let blocked_domains = rule
+            .blocked_domains
+            .iter()
+            .map(|domain| domain.trim().trim_start_matches("www.").to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+
+        Ok(Self {
+            id,
+            rule,
+            keywords,
+            patterns,
+            blocked_domains,
+        })
+    }
+
+    /// Why the rule fires for the post, or `None` if it doesn't.
+    fn evaluate(&self, post: &Post, author: &Author) -> Option<String> {
+        if !(self.rule.applies_to.is_empty() || self.rule.applies_to.contains(&post.kind)) {
+            return None;
+        }
+
+        let mut reasons = Vec::new();
+
+        if self.rule.has_content_conditions() {
+            let text = match post.title {
+                Some(title) => format!("{}\n{}", title, post.content),
+                None => post.content.to_string(),
+            };
+
+            if let Some(keyword) = self.keywords.as_ref().and_then(|re| re.find(&text)) {
+                reasons.push(format!("contains \"{}\"", keyword.as_str()));
+            } else if self.patterns.is_match(&text) {
+                reasons.push("matches a blocked pattern".to_string());
+            } else if let Some(domain) = linked_domains(&text).find(|domain| self.blocks(domain)) {
+                reasons.push(format!("links to {}", domain));
+            } else {
+                return None;
+            }
+        }
+
+        if self.rule.has_author_conditions() {
+            let too_new = self
+                .rule
+                .min_account_age_hours
+                .is_some_and(|hours| Utc::now() - author.created_at < Duration::hours(hours));
+            let too_little_karma = self.rule.min_karma.is_some_and(|min| author.karma < min);
+
+            if too_new {
+                reasons.push("account is too new".to_string());
+            } else if too_little_karma {
+                reasons.push("not enough karma".to_string());
+            } else {
+                return None;
+            }
+        }
+
+        Some(match &self.rule.message {
+            Some(message) => message.clone(),
+            None => reasons.join(", "),
+        })
+    }
+
+    fn blocks(&self, domain: &str) -> bool {
+        self.blocked_domains.iter().any(|blocked| {
+            domain == blocked
+                || domain
+                    .strip_suffix(blocked.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+}
+
+/// Matches `keyword` as a whole word. Word boundaries only make sense next to
+/// word characters, so a keyword like `c++` or `.ru` only gets one on the side
+/// that has one, and `$$$` gets none.
+fn keyword_pattern(keyword: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let boundary = |c| if is_word(c) { r"\b" } else { "" };
+
+    format!(
+        "(?:{}{}{})",
+        boundary(keyword.chars().next()),
+        regex::escape(keyword),
+        boundary(keyword.chars().next_back()),
+    )
+}
+
+/// The domains of the links in `text`, lowercased and without `www.`.
+fn linked_domains(text: &str) -> impl Iterator<Item = String> + '_ {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+
+    LINK.get_or_init(|| {
+        Regex::new(r"(?i)\b(?:https?://)?(?:www\.)?((?:[a-z0-9-]+\.)+[a-z]{2,})\b").unwrap()
+    })
+    .captures_iter(text)
+    .map(|captures| captures[1].to_lowercase())
+}
+
+pub struct Post<'a> {
+    pub kind: PostKind,
+    pub title: Option<&'a str>,
+    pub content: &'a str,
+}
+
+pub struct Author {
+    pub created_at: DateTime<Utc>,
+    pub karma: i64,
+    pub role: Role,
+}
+
+impl Author {
+    pub async fn load(db: impl PgExecutor<'_>, user_id: i64) -> Result<Self, Error> {
+        let author = sqlx::query!(
+            r#"
+                select
+                    created_at,
+                    role,
+                    (
+                        (
+                            select count(*)
+                            from thread_votes a
+                            join threads b on a.thread_id = b.id
+                            where b.user_id = $1
+                        ) + (
+                            select count(*)
+                            from comment_votes a
+                            join comments b on a.comment_id = b.id
+                            where b.user_id = $1
+                        )
+                    ) as "karma!"
+                from users
+                where id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+        Ok(Self {
+            created_at: author.created_at,
+            karma: author.karma,
+            role: Role::from_db(&author.role),
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub action: RuleAction,
+    pub reason: String,
+}
+
+/// The rules that fired for a post.
+#[derive(Serialize, Default, Debug)]
+pub struct Verdict {
+    pub matches: Vec<RuleMatch>,
+}
+
+impl Verdict {
+    pub fn is_held(&self) -> bool {
+        self.matches.iter().any(|m| m.action == RuleAction::Hold)
+    }
+}
+
+/// Runs `rules` against a post.
+pub fn evaluate<'a>(
+    rules: impl IntoIterator<Item = &'a CompiledRule>,
+    post: &Post,
+    author: &Author,
+) -> Verdict {
+    let matches = rules
+        .into_iter()
+        .filter_map(|rule| {
+            rule.evaluate(post, author).map(|reason| RuleMatch {
+                rule: rule.rule.name.clone(),
+                action: rule.rule.action,
+                reason,
+            })
+        })
+        .collect();
+
+    Verdict { matches }
+}
+
+/// The active automoderator rules: those from the rules file, which can only
+/// be changed by editing the file and restarting, followed by those stored in
+/// the database.
+pub struct AutoMod {
+    file_rules: Vec<CompiledRule>,
+    db_rules: RwLock<Vec<CompiledRule>>,
+}
+
+impl AutoMod {
+    pub fn new(file_rules: Vec<CompiledRule>) -> Self {
+        Self {
+            file_rules,
+            db_rules: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Reads a JSON array of rules from `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let rules: Vec<Rule> = serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let name = rule.name.clone();
+                CompiledRule::new(None, rule)
+                    .map_err(|e| anyhow::anyhow!("Invalid rule {:?}: {:?}", name, e))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self::new(rules))
+    }
+
+    /// Reloads the rules stored in the database. Stored rules that no longer
+    /// compile are skipped.
+    pub async fn reload(&self, db: &PgPool) -> Result<(), Error> {
+        let rows = sqlx::query!(
+            r#"
+                select id, rule as "rule: sqlx::types::Json<Rule>"
+                from automod_rules
+                order by id
+            "#
+        )
+        .fetch_all(db)
+        .await?;
+
+        let rules = rows
+            .into_iter()
+            .filter_map(|row| CompiledRule::new(Some(row.id), row.rule.0).ok())
+            .collect();
+
+        *self.db_rules.write().unwrap() = rules;
+
+        Ok(())
+    }
+
+    /// Runs the active rules against a post.
+    pub fn evaluate(&self, post: &Post, author: &Author) -> Verdict {
+        let db_rules = self.db_rules.read().unwrap();
+        evaluate(self.file_rules.iter().chain(db_rules.iter()), post, author)
+    }
+
+    /// The active rules, as `(id, rule)` pairs.
+    pub fn rules(&self) -> Vec<(Option<i64>, Rule)> {
+        let db_rules = self.db_rules.read().unwrap();
+
+        self.file_rules
+            .iter()
+            .chain(db_rules.iter())
+            .map(|rule| (rule.id, rule.rule.clone()))
+            .collect()
+    }
+
+    /// Checks a new or edited post against the active rules, failing with the
+    /// reason of the first rejecting rule. Moderators aren't subject to the
+    /// automoderator.
+    pub(crate) async fn check(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: i64,
+        post: Post<'_>,
+    ) -> Result<Verdict, Error> {
+        let author = Author::load(&mut *tx, user_id).await?;
+
+        if author.role >= Role::Moderator {
+            return Ok(Verdict::default());
+        }
+
+        let verdict = self.evaluate(&post, &author);
+
+        if let Some(rejected) = verdict
+            .matches
+            .iter()
+            .find(|m| m.action == RuleAction::Reject)
+        {
+            return Err(Error::unprocessable_entity([(
+                "content",
+                rejected.reason.clone(),
+            )]));
+        }
+
+        Ok(verdict)
+    }
+}
+
+/// Files a report on the post for every rule that held or flagged it, so it
+/// shows up in the mod queue.
+pub(crate) async fn report(
+    tx: &mut Transaction<'_, Postgres>,
+    kind: PostKind,
+    id: i64,
+    verdict: &Verdict,
+) -> Result<(), Error> {
+    for m in &verdict.matches {
+        sqlx::query!(
+            "
+                insert into reports(target_kind, target_id, reason, note)
+                values($1, $2, 'automod', $3)
+            ",
+            kind.as_str(),
+            id,
+            format!("{}: {}", m.rule, m.reason)
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str) -> Rule {
+        Rule {
+            name: name.to_string(),
+            applies_to: Vec::new(),
+            keywords: Vec::new(),
+            patterns: Vec::new(),
+            blocked_domains: Vec::new(),
+            min_account_age_hours: None,
+            min_karma: None,
+            action: RuleAction::Reject,
+            message: None,
+        }
+    }
+
+    fn comment(content: &str) -> Post<'_> {
+        Post {
+            kind: PostKind::Comment,
+            title: None,
+            content,
+        }
+    }
+
+    fn author(age_hours: i64, karma: i64) -> Author {
+        Author {
+            created_at: Utc::now() - Duration::hours(age_hours),
+            karma,
+            role: Role::User,
+        }
+    }
+
+    fn keywords(keywords: &[&str]) -> CompiledRule {
+        let rule = Rule {
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            ..rule("keywords")
+        };
+
+        CompiledRule::new(None, rule).unwrap()
+    }
+
+    fn blocked_domains(domains: &[&str]) -> CompiledRule {
+        let rule = Rule {
+            blocked_domains: domains.iter().map(|d| d.to_string()).collect(),
+            ..rule("domains")
+        };
+
+        CompiledRule::new(None, rule).unwrap()
+    }
+
+    fn fires(rule: &CompiledRule, content: &str) -> bool {
+        rule.evaluate(&comment(content), &author(1000, 1000))
+            .is_some()
+    }
+
+    #[test]
+    fn keywords_match_whole_words_in_any_case() {
+        let rule = keywords(&["spam"]);
+
+        assert!(fires(&rule, "Buy SPAM now"));
+        assert!(!fires(&rule, "spammer"));
+        assert!(!fires(&rule, "antispam"));
+    }
+
+    #[test]
+    fn keywords_can_start_or_end_with_punctuation() {
+        assert!(fires(&keywords(&["c++"]), "learn c++ today"));
+        assert!(!fires(&keywords(&["c++"]), "abc++"));
+        assert!(fires(&keywords(&["$$$"]), "make $$$ fast"));
+        assert!(fires(&keywords(&[".ru"]), "visit shop.ru"));
+        assert!(!fires(&keywords(&[".ru"]), "shop.rules"));
+    }
+
+    #[test]
+    fn patterns_match_the_title_too() {
+        let rule = Rule {
+            patterns: vec![r"\d{3}-\d{4}".to_string()],
+            ..rule("patterns")
+        };
+        let rule = CompiledRule::new(None, rule).unwrap();
+        let post = Post {
+            kind: PostKind::Thread,
+            title: Some("call 555-1234"),
+            content: "hi",
+        };
+
+        assert!(rule.evaluate(&post, &author(1000, 1000)).is_some());
+    }
+
+    #[test]
+    fn blocks_domains_and_their_subdomains() {
+        let rule = blocked_domains(&["www.example.com"]);
+
+        assert!(fires(&rule, "see https://example.com/page"));
+        assert!(fires(&rule, "see http://www.Example.com"));
+        assert!(fires(&rule, "see shop.example.com"));
+        assert!(!fires(&rule, "see notexample.com"));
+        assert!(!fires(&rule, "see example.com.evil.org"));
+    }
+
+    #[test]
+    fn finds_linked_domains() {
+        let domains =
+            linked_domains("a https://www.Foo.org/x and bar.co.uk, not foo").collect::<Vec<_>>();
+
+        assert_eq!(domains, ["foo.org", "bar.co.uk"]);
+    }
+
+    #[test]
+    fn author_conditions_need_content_conditions_to_match_first() {
+        let rule = Rule {
+            keywords: vec!["spam".to_string()],
+            min_account_age_hours: Some(24),
+            ..rule("new accounts")
+        };
+        let rule = CompiledRule::new(None, rule).unwrap();
+
+        assert!(rule.evaluate(&comment("spam"), &author(1, 0)).is_some());
+        assert!(rule.evaluate(&comment("spam"), &author(48, 0)).is_none());
+        assert!(rule.evaluate(&comment("hello"), &author(1, 0)).is_none());
+    }
+
+    #[test]
+    fn author_conditions_alone_apply_to_every_post() {
+        let rule = Rule {
+            min_karma: Some(10),
+            ..rule("low karma")
+        };
+        let rule = CompiledRule::new(None, rule).unwrap();
+
+        assert!(rule.evaluate(&comment("hello"), &author(1000, 5)).is_some());
+        assert!(rule
+            .evaluate(&comment("hello"), &author(1000, 10))
+            .is_none());
+    }
+
+    #[test]
+    fn skips_other_kinds_of_posts() {
+        let rule = Rule {
+            applies_to: vec![PostKind::Thread],
+            keywords: vec!["spam".to_string()],
+            ..rule("threads")
+        };
+        let rule = CompiledRule::new(None, rule).unwrap();
+
+        assert!(!fires(&rule, "spam"));
+    }
+
+    #[test]
+    fn uses_the_rule_message_when_set() {
+        let rule = Rule {
+            keywords: vec!["spam".to_string()],
+            message: Some("No spam, please".to_string()),
+            ..rule("spam")
+        };
+        let rule = CompiledRule::new(None, rule).unwrap();
+        let verdict = evaluate([&rule], &comment("spam"), &author(1000, 1000));
+
+        assert_eq!(verdict.matches[0].reason, "No spam, please");
+        assert!(!verdict.is_held());
+    }
+
+    #[test]
+    fn rejects_out_of_range_author_conditions() {
+        for (age, karma) in [
+            (Some(-1), None),
+            (Some(i64::MAX), None),
+            (None, Some(-1)),
+            (None, Some(MAX_MIN_KARMA + 1)),
+        ] {
+            let rule = Rule {
+                min_account_age_hours: age,
+                min_karma: karma,
+                ..rule("bad")
+            };
+
+            assert!(CompiledRule::new(None, rule).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_rules_without_conditions() {
+        assert!(CompiledRule::new(None, rule("empty")).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use forum::admin;
+use forum::auth::Role;
+use forum::db;
+use sqlx::postgres::PgPoolOptions;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Operates the forum directly against its database.
+#[derive(Parser)]
+#[command(name = "forum-admin")]
+struct Cli {
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    database_url: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates an account, reading its password from stdin.
+    CreateUser {
+        username: String,
+        #[arg(long, value_enum, default_value_t = RoleArg::User)]
+        role: RoleArg,
+    },
+    /// Makes a user a moderator or admin.
+    Grant {
+        username: String,
+        #[arg(value_enum)]
+        role: RoleArg,
+        /// The admin to record in the moderation log.
+        #[arg(long = "as")]
+        actor: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Takes a user's moderator or admin role away.
+    Revoke {
+        username: String,
+        /// The admin to record in the moderation log.
+        #[arg(long = "as")]
+        actor: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Bans, suspends or shadowbans a user.
+    Ban {
+        username: String,
+        #[arg(long, value_enum, default_value_t = BanKindArg::Ban)]
+        kind: BanKindArg,
+        #[arg(long)]
+        reason: String,
+        /// Required for suspensions; not allowed for bans.
+        #[arg(long)]
+        hours: Option<i64>,
+        /// The moderator to record in the moderation log.
+        #[arg(long = "as")]
+        actor: String,
+    },
+    /// Sets a new password for a user, read from stdin.
+    ResetPassword { username: String },
+    /// Moves one account's posts, votes and follows to another and bans it.
+    MergeUsers {
+        from: String,
+        into: String,
+        /// The admin to record in the moderation log.
+        #[arg(long = "as")]
+        actor: String,
+    },
+    /// Removes every thread and comment a user has posted.
+    DeleteSpam {
+        username: String,
+        /// The moderator to record in the moderation log.
+        #[arg(long = "as")]
+        actor: String,
+    },
+    /// Recomputes stored counters from scratch. Karma is counted on read, so
+    /// it never needs recomputing.
+    Recompute,
+    /// Applies pending database migrations.
+    Migrate,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RoleArg {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl From<RoleArg> for Role {
+    fn from(role: RoleArg) -> Self {
+        match role {
+            RoleArg::User => Role::User,
+            RoleArg::Moderator => Role::Moderator,
+            RoleArg::Admin => Role::Admin,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BanKindArg {
+    Suspension,
+    Ban,
+    Shadowban,
+}
+
+impl BanKindArg {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Suspension => "suspension",
+            Self::Ban => "ban",
+            Self::Shadowban => "shadowban",
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+
+    let cli = Cli::parse();
+
+    let db = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&cli.database_url)
+        .await
+        .context("could not connect to database")?;
+
+    match cli.command {
+        Command::CreateUser { username, role } => {
+            let password = read_password()?;
+            let id = admin::create_user(&db, &username, &password, role.into()).await?;
+            println!("Created {} with id {}", username, id);
+        }
+        Command::Grant {
+            username,
+            role,
+            actor,
+            reason,
+        } => {
+            let role = Role::from(role);
+            let before =
+                admin::set_role(&db, &actor, &username, role, reason.as_deref()).await?;
+            println!(
+                "Changed {} from {} to {}",
+                username,
+                before.as_str(),
+                role.as_str()
+            );
+        }
+        Command::Revoke {
+            username,
+            actor,
+            reason,
+        } => {
+            let before =
+                admin::set_role(&db, &actor, &username, Role::User, reason.as_deref()).await?;
+            println!("Changed {} from {} to user", username, before.as_str());
+        }
+        Command::Ban {
+            username,
+            kind,
+            reason,
+            hours,
+            actor,
+        } => {
+            let id = admin::ban(&db, &actor, &username, kind.as_str(), &reason, hours).await?;
+            println!("Created {} {} for {}", kind.as_str(), id, username);
+        }
+        Command::ResetPassword { username } => {
+            let password = read_password()?;
+            admin::reset_password(&db, &username, &password).await?;
+            println!("Reset the password of {}", username);
+        }
+        Command::MergeUsers { from, into, actor } => {
+            admin::merge_users(&db, &actor, &from, &into).await?;
+            println!("Merged {} into {}", from, into);
+        }
+        Command::DeleteSpam { username, actor } => {
+            let removed = admin::delete_spam(&db, &actor, &username).await?;
+            println!(
+                "Removed {} threads and {} comments by {}",
+                removed.threads, removed.comments, username
+            );
+        }
+        Command::Recompute => {
+            let recomputed = admin::recompute_counters(&db).await?;
+            println!("Recomputed {} conversations", recomputed.conversations);
+        }
+        Command::Migrate => {
+            db::migrate(&db).await?;
+            println!("Migrations are up to date");
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a password from the first line of stdin, prompting for it when stdin
+/// is a terminal. Passwords aren't taken as arguments so they stay out of the
+/// shell history and the process list.
+fn read_password() -> anyhow::Result<String> {
+    let stdin = io::stdin();
+
+    if stdin.is_terminal() {
+        eprint!("Password: ");
+        io::stderr().flush()?;
+    }
+
+    let mut password = String::new();
+    stdin
+        .lock()
+        .read_line(&mut password)
+        .context("could not read the password")?;
+
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    if password.is_empty() {
+        anyhow::bail!("the password can't be empty");
+    }
+
+    Ok(password.to_string())
+}
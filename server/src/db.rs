@@ -0,0 +1,32 @@
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::PgPool;
+
+/// The migrations in `migrations/`, embedded at build time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Applies any pending migrations. Fails without applying anything if a
+/// migration that was already applied has since been changed, or is missing
+/// from this build.
+pub async fn migrate(db: &PgPool) -> Result<(), MigrateError> {
+    MIGRATOR.run(db).await
+}
+
+/// The versions of the embedded migrations that haven't been applied.
+pub async fn pending_migrations(db: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    // Not checked at compile time, as the table only exists once the migrator has run.
+    let applied: Vec<i64> = sqlx::query_scalar(
+        "
+            select version
+            from _sqlx_migrations
+            where success
+        ",
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
@@ -1,11 +1,13 @@
+use crate::telemetry;
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use chrono::{DateTime, Utc};
 use serde::Serialize;
 use sqlx::error::DatabaseError;
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, time::Duration};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -17,6 +19,13 @@ pub enum Error {
     #[error("User may not perform that action")]
     Forbidden,
 
+    /// Return `403 Forbidden` for a write by a suspended or banned user
+    #[error("Account is banned")]
+    Banned {
+        reason: String,
+        expires_at: Option<DateTime<Utc>>,
+    },
+
     /// Return `404 Not Found`
     #[error("Requst path not found")]
     NotFound,
@@ -27,6 +36,10 @@ pub enum Error {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
     },
 
+    /// Return `429 Too Many Requests` with a `Retry-After` header
+    #[error("Too many requests, try again later")]
+    TooManyRequests { retry_after: Duration },
+
     /// Return `500 Internal Server Error` on a `sqlx::Error`
     #[error("An error occurred with the database")]
     Sqlx(#[from] sqlx::Error),
@@ -57,32 +70,88 @@ impl Error {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
-            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Forbidden | Self::Banned { .. } => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    /// The variant's name, as the `variant` label of `errors_total`.
+    fn variant(&self) -> &'static str {
+        match self {
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::Banned { .. } => "banned",
+            Self::NotFound => "not_found",
+            Self::UnprocessableEntity { .. } => "unprocessable_entity",
+            Self::TooManyRequests { .. } => "too_many_requests",
+            Self::Sqlx(_) => "sqlx",
+            Self::Anyhow(_) => "anyhow",
+        }
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
+        metrics::increment_counter!("errors_total", "variant" => self.variant());
+
         match self {
             Self::UnprocessableEntity { errors } => {
                 #[derive(Serialize)]
                 struct Errors {
                     errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
+                    #[serde(skip_serializing_if = "Option::is_none")]
+                    request_id: Option<String>,
                 }
 
-                return (StatusCode::UNPROCESSABLE_ENTITY, Json(Errors { errors })).into_response();
+                let request_id = telemetry::request_id();
+
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(Errors { errors, request_id }),
+                )
+                    .into_response();
             }
-            Self::Unauthorized => return (self.status_code(), self.to_string()).into_response(),
-            Self::Sqlx(ref e) => log::error!("SQLx error: {:?}", e),
-            Self::Anyhow(ref e) => log::error!("Generic error: {:?}", e),
+            Self::Sqlx(ref e) => tracing::error!("SQLx error: {:?}", e),
+            Self::Anyhow(ref e) => tracing::error!("Generic error: {:?}", e),
             _ => (),
         }
 
-        (self.status_code(), self.to_string()).into_response()
+        let message = match self {
+            Self::Banned {
+                ref reason,
+                expires_at: Some(expires_at),
+            } => format!(
+                "Account is suspended until {}: {}",
+                expires_at.to_rfc3339(),
+                reason
+            ),
+            Self::Banned {
+                ref reason,
+                expires_at: None,
+            } => format!("Account is banned: {}", reason),
+            _ => self.to_string(),
+        };
+
+        // So users can quote it when reporting the error.
+        let message = match telemetry::request_id() {
+            Some(request_id) => format!("{} (request id {})", message, request_id),
+            None => message,
+        };
+
+        let mut response = (self.status_code(), message).into_response();
+
+        if let Self::TooManyRequests { retry_after } = self {
+            // Round up so clients never retry a moment too early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+
+        response
     }
 }
 
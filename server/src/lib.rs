@@ -1,3 +1,14 @@
+pub mod admin;
 pub mod auth;
+pub mod automod;
+pub mod db;
 pub mod error;
+pub mod mentions;
+pub mod mod_log;
+pub mod notifications;
+pub mod rate_limit;
 pub mod routes;
+pub mod shutdown;
+pub mod storage;
+pub mod telemetry;
+pub mod validate;
@@ -1,5 +1,15 @@
+use forum::automod::AutoMod;
+use forum::db;
+use forum::rate_limit::RateLimiter;
 use forum::routes;
-use sqlx::postgres::PgPoolOptions;
+use forum::shutdown::{self, Shutdown};
+use forum::storage::LocalStorage;
+use forum::telemetry;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::ConnectOptions;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -7,18 +17,124 @@ async fn main() {
     // Will need to remove this when deployed to production.
     dotenvy::dotenv().ok();
 
+    telemetry::init();
+
     let db_url = dotenvy::var("DATABASE_URL").unwrap();
 
+    // Queries are logged at debug level, within the span of the request that ran them.
+    let mut connect_options: PgConnectOptions = db_url.parse().expect("invalid DATABASE_URL");
+    connect_options
+        .log_statements(tracing::log::LevelFilter::Debug)
+        .log_slow_statements(tracing::log::LevelFilter::Warn, Duration::from_millis(500));
+
     let db = PgPoolOptions::new()
         .max_connections(50)
-        .connect(&db_url)
+        .connect_with(connect_options)
         .await
         .expect("cound not connect to database");
 
-    let app = routes::router(db);
+    db::migrate(&db).await.expect("could not run migrations");
+
+    // `forum migrate` only brings the schema up to date.
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        return;
+    }
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
+    let media_dir = dotenvy::var("MEDIA_DIR").unwrap_or_else(|_| "media".to_string());
+    let storage = LocalStorage::new(media_dir, "/media");
+    let media = storage.service();
+
+    let automod = match dotenvy::var("AUTOMOD_RULES") {
+        Ok(path) => AutoMod::from_file(path).expect("could not load automod rules"),
+        Err(_) => AutoMod::new(Vec::new()),
+    };
+    automod
+        .reload(&db)
         .await
-        .unwrap();
+        .expect("could not load automod rules from database");
+
+    // How long to let in-flight requests finish after a shutdown signal.
+    let drain_timeout = dotenvy::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .map(|secs| {
+            secs.parse()
+                .expect("SHUTDOWN_TIMEOUT_SECS must be a number")
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(30));
+
+    // Addresses of the reverse proxies in front of the server, whose
+    // X-Forwarded-For says who the client is.
+    let trusted_proxies = dotenvy::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| proxy.parse().expect("invalid address in TRUSTED_PROXIES"))
+        .collect();
+
+    let shutdown = Shutdown::new();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            tracing::info!("Shutting down, draining connections");
+            shutdown.trigger();
+        }
+    });
+
+    // Metrics are only recorded when there's somewhere to serve them, which
+    // should be an address the public can't reach.
+    let metrics_server = dotenvy::var("METRICS_ADDR").ok().map(|addr| {
+        let addr: SocketAddr = addr.parse().expect("invalid METRICS_ADDR");
+        let metrics = telemetry::metrics_router(telemetry::install_metrics(), db.clone());
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let server = axum::Server::bind(&addr)
+                .serve(metrics.into_make_service())
+                .with_graceful_shutdown(shutdown.triggered());
+
+            if let Err(e) = server.await {
+                tracing::error!("Metrics server failed: {}", e);
+            }
+        })
+    });
+
+    let app = routes::router(
+        db.clone(),
+        Arc::new(storage),
+        Arc::new(automod),
+        RateLimiter::new(trusted_proxies),
+        shutdown.clone(),
+    )
+    .nest_service("/media", media);
+
+    let server = axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown.triggered());
+
+    // The metrics server samples the pool, so it has to be done before the
+    // pool is closed. It's the only task the server spawns besides the
+    // signal handler.
+    let drain = async {
+        server.await.unwrap();
+
+        if let Some(metrics_server) = metrics_server {
+            if let Err(e) = metrics_server.await {
+                tracing::error!("Metrics server panicked: {}", e);
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = drain => {}
+        _ = async {
+            shutdown.triggered().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => tracing::warn!("Timed out draining connections"),
+    }
+
+    db.close().await;
 }
@@ -0,0 +1,198 @@
+use crate::error::Error;
+use crate::notifications::{notify, NewNotification, NotificationCreated, NotificationKind};
+use sqlx::{Postgres, Transaction};
+
+/// Only the first few distinct usernames in a post are treated as mentions.
+const MAX_MENTIONS_PER_POST: usize = 10;
+
+/// Cap on mention notifications a single author can send in an hour.
+const MAX_MENTION_NOTIFICATIONS_PER_HOUR: i64 = 50;
+
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Returns the distinct `@username` mentions in `content`, in order of appearance.
+///
+/// An `@` only starts a mention at the beginning of the text or after a character
+/// that can't be part of a username, so email addresses aren't picked up.
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    let mut prev = None;
+
+    for (i, c) in content.char_indices() {
+        if c == '@' && !prev.is_some_and(is_username_char) {
+            let rest = &content[i + 1..];
+            let end = rest.find(|c| !is_username_char(c)).unwrap_or(rest.len());
+            let username = &rest[..end];
+
+            if !username.is_empty() && !mentions.iter().any(|m| m == username) {
+                mentions.push(username.to_string());
+
+                if mentions.len() == MAX_MENTIONS_PER_POST {
+                    break;
+                }
+            }
+        }
+
+        prev = Some(c);
+    }
+
+    mentions
+}
+
+/// Records the users mentioned in a thread (`comment_id` of `None`) or comment,
+/// and, if `send_notifications` is set, notifies them, returning the notifications that were
+/// created.
+///
+/// Users already recorded for the same post are skipped, and those no longer
+/// mentioned are removed, so this can be called again with edited content to
+/// only pick up the new mentions. Authors aren't
+/// notified of mentioning themselves, and users who have blocked the author
+/// aren't notified at all.
+pub(crate) async fn record_mentions(
+    tx: &mut Transaction<'_, Postgres>,
+    author_user_id: i64,
+    thread_id: i64,
+    comment_id: Option<i64>,
+    content: &str,
+    send_notifications: bool,
+) -> Result<Vec<NotificationCreated>, Error> {
+    let usernames = parse_mentions(content);
+
+    sqlx::query!(
+        "
+            delete from mentions
+            where thread_id = $1
+                and comment_id is not distinct from $2
+                and user_id not in (
+                    select id
+                    from users
+                    where username = any($3)
+                )
+        ",
+        thread_id,
+        comment_id,
+        &usernames
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if usernames.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mentioned = sqlx::query_scalar!(
+        r#"
+            with inserted as (
+                insert into mentions(user_id, author_user_id, thread_id, comment_id)
+                select id, $2, $3, $4
+                from users
+                where username = any($1)
+                    and not exists(
+                        select *
+                        from mentions
+                        where user_id = users.id
+                            and thread_id = $3
+                            and comment_id is not distinct from $4
+                    )
+                returning user_id
+            )
+            select user_id as "user_id!"
+            from inserted
+            where user_id <> $2
+                and not exists(
+                    select *
+                    from blocks
+                    where blocker_user_id = inserted.user_id
+                        and blocked_user_id = $2
+                )
+        "#,
+        &usernames,
+        author_user_id,
+        thread_id,
+        comment_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if !send_notifications {
+        return Ok(Vec::new());
+    }
+
+    let sent = sqlx::query_scalar!(
+        r#"
+            select count(*) as "count!"
+            from notifications
+            where actor_user_id = $1
+                and kind = $2
+                and created_at > now() - interval '1 hour'
+        "#,
+        author_user_id,
+        NotificationKind::Mention.as_str()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let budget = (MAX_MENTION_NOTIFICATIONS_PER_HOUR - sent).max(0) as usize;
+
+    let mut created = Vec::new();
+
+    for user_id in mentioned.into_iter().take(budget) {
+        created.extend(
+            notify(
+                tx,
+                NewNotification {
+                    user_id,
+                    actor_user_id: author_user_id,
+                    kind: NotificationKind::Mention,
+                    thread_id: Some(thread_id),
+                    comment_id,
+                },
+            )
+            .await?,
+        );
+    }
+
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mentions_in_order() {
+        assert_eq!(
+            parse_mentions("@alice and @bob-2, then @carol_3."),
+            ["alice", "bob-2", "carol_3"]
+        );
+    }
+
+    #[test]
+    fn skips_duplicates() {
+        assert_eq!(parse_mentions("@alice @bob @alice"), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn ignores_email_addresses_and_bare_ats() {
+        assert!(parse_mentions("mail alice@example.com or just @ alone").is_empty());
+    }
+
+    #[test]
+    fn mentions_can_follow_punctuation() {
+        assert_eq!(parse_mentions("(@alice),@bob"), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn allows_non_ascii_usernames() {
+        assert_eq!(parse_mentions("hej @björn"), ["björn"]);
+    }
+
+    #[test]
+    fn caps_mentions_per_post() {
+        let content = (0..20).map(|i| format!("@user{} ", i)).collect::<String>();
+
+        assert_eq!(parse_mentions(&content).len(), MAX_MENTIONS_PER_POST);
+    }
+}
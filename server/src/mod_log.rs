@@ -0,0 +1,177 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModAction {
+    RemoveThread,
+    RemoveComment,
+    RemoveMessage,
+    ResolveReports,
+    DismissReports,
+    LockThread,
+    UnlockThread,
+    PinThread,
+    UnpinThread,
+    Ban,
+    LiftBans,
+    ChangeRole,
+    EditThread,
+    EditComment,
+    CreateAutomodRule,
+    DeleteAutomodRule,
+    MergeUsers,
+}
+
+impl ModAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RemoveThread => "remove_thread",
+            Self::RemoveComment => "remove_comment",
+            Self::RemoveMessage => "remove_message",
+            Self::ResolveReports => "resolve_reports",
+            Self::DismissReports => "dismiss_reports",
+            Self::LockThread => "lock_thread",
+            Self::UnlockThread => "unlock_thread",
+            Self::PinThread => "pin_thread",
+            Self::UnpinThread => "unpin_thread",
+            Self::Ban => "ban",
+            Self::LiftBans => "lift_bans",
+            Self::ChangeRole => "change_role",
+            Self::EditThread => "edit_thread",
+            Self::EditComment => "edit_comment",
+            Self::CreateAutomodRule => "create_automod_rule",
+            Self::DeleteAutomodRule => "delete_automod_rule",
+            Self::MergeUsers => "merge_users",
+        }
+    }
+}
+
+pub(crate) struct ModLogEntry<'a> {
+    pub actor_user_id: i64,
+    pub action: ModAction,
+    /// What kind of row `target_id` refers to, e.g. `thread`, `comment` or `user`.
+    pub target_kind: &'static str,
+    pub target_id: i64,
+    /// The user whose content or account was acted on.
+    pub target_user_id: Option<i64>,
+    pub reason: Option<&'a str>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+/// Appends an entry to the moderation log, in the same transaction as the
+/// action it records.
+pub(crate) async fn record(
+    tx: &mut Transaction<'_, Postgres>,
+    entry: ModLogEntry<'_>,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "
+            insert into mod_log(
+                actor_user_id,
+                action,
+                target_kind,
+                target_id,
+                target_user_id,
+                reason,
+                before,
+                after
+            )
+            values($1, $2, $3, $4, $5, $6, $7, $8)
+        ",
+        entry.actor_user_id,
+        entry.action.as_str(),
+        entry.target_kind,
+        entry.target_id,
+        entry.target_user_id,
+        entry.reason,
+        entry.before,
+        entry.after
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// The owner of a row and a JSON snapshot of the fields moderators can change.
+pub(crate) struct Snapshot {
+    pub user_id: i64,
+    pub value: serde_json::Value,
+}
+
+pub(crate) async fn thread_snapshot(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i64,
+) -> Result<Option<Snapshot>, Error> {
+    Ok(sqlx::query_as!(
+        Snapshot,
+        r#"
+            select
+                user_id,
+                jsonb_build_object(
+                    'slug', slug,
+                    'title', title,
+                    'content', content,
+                    'removed_at', removed_at,
+                    'locked_at', locked_at,
+                    'pinned_at', pinned_at,
+                    'held_at', held_at
+                ) as "value!"
+            from threads
+            where id = $1
+        "#,
+        id
+    )
+    .fetch_optional(tx)
+    .await?)
+}
+
+pub(crate) async fn comment_snapshot(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i64,
+) -> Result<Option<Snapshot>, Error> {
+    Ok(sqlx::query_as!(
+        Snapshot,
+        r#"
+            select
+                user_id,
+                jsonb_build_object(
+                    'thread_id', thread_id,
+                    'content', content,
+                    'removed_at', removed_at,
+                    'held_at', held_at
+                ) as "value!"
+            from comments
+            where id = $1
+        "#,
+        id
+    )
+    .fetch_optional(tx)
+    .await?)
+}
+
+pub(crate) async fn message_snapshot(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i64,
+) -> Result<Option<Snapshot>, Error> {
+    Ok(sqlx::query_as!(
+        Snapshot,
+        r#"
+            select
+                sender_user_id as user_id,
+                jsonb_build_object(
+                    'conversation_id', conversation_id,
+                    'content', content,
+                    'removed_at', removed_at
+                ) as "value!"
+            from messages
+            where id = $1
+        "#,
+        id
+    )
+    .fetch_optional(tx)
+    .await?)
+}
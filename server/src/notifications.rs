@@ -0,0 +1,163 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// Someone replied to one of the user's comments.
+    Reply,
+    /// Someone left a top-level comment on one of the user's threads.
+    ThreadComment,
+    /// Someone mentioned the user in a thread or comment.
+    Mention,
+    /// Someone started following the user.
+    Follow,
+    /// Someone commented on a thread the user is watching.
+    WatchedComment,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Reply => "reply",
+            Self::ThreadComment => "thread_comment",
+            Self::Mention => "mention",
+            Self::Follow => "follow",
+            Self::WatchedComment => "watched_comment",
+        }
+    }
+}
+
+pub(crate) struct NewNotification {
+    pub user_id: i64,
+    pub actor_user_id: i64,
+    pub kind: NotificationKind,
+    pub thread_id: Option<i64>,
+    pub comment_id: Option<i64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NotificationCreated {
+    pub id: i64,
+    #[serde(skip)]
+    pub user_id: i64,
+    pub kind: NotificationKind,
+}
+
+/// Creates a notification unless the user would be notifying themselves, has
+/// muted notifications of that kind or from that thread, or has blocked the actor.
+pub(crate) async fn notify(
+    tx: &mut Transaction<'_, Postgres>,
+    notification: NewNotification,
+) -> Result<Option<NotificationCreated>, Error> {
+    if notification.user_id == notification.actor_user_id {
+        return Ok(None);
+    }
+
+    let id = sqlx::query_scalar!(
+        "
+            insert into notifications(user_id, actor_user_id, kind, thread_id, comment_id)
+            select $1, $2, $3, $4, $5
+            where not exists(
+                select *
+                from notification_mutes
+                where user_id = $1
+                    and kind = $3
+            )
+                and not exists(
+                    select *
+                    from thread_mutes
+                    where user_id = $1
+                        and thread_id = $4
+                )
+                and not exists(
+                    select *
+                    from blocks
+                    where blocker_user_id = $1
+                        and blocked_user_id = $2
+                )
+            returning id
+        ",
+        notification.user_id,
+        notification.actor_user_id,
+        notification.kind.as_str(),
+        notification.thread_id,
+        notification.comment_id
+    )
+    .fetch_optional(tx)
+    .await?;
+
+    Ok(id.map(|id| NotificationCreated {
+        id,
+        user_id: notification.user_id,
+        kind: notification.kind,
+    }))
+}
+
+/// Watches a thread for the user, if they have auto-watch turned on.
+pub(crate) async fn auto_watch(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    thread_id: i64,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "
+            insert into thread_watches(user_id, thread_id)
+            select id, $2
+            from users
+            where id = $1
+                and auto_watch
+            on conflict do nothing
+        ",
+        user_id,
+        thread_id
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Notifies everyone watching a thread of a new comment on it, except the
+/// users in `skip`, who were already notified of it some other way.
+pub(crate) async fn notify_watchers(
+    tx: &mut Transaction<'_, Postgres>,
+    actor_user_id: i64,
+    thread_id: i64,
+    comment_id: i64,
+    skip: &[i64],
+) -> Result<Vec<NotificationCreated>, Error> {
+    let watchers = sqlx::query_scalar!(
+        "
+            select user_id
+            from thread_watches
+            where thread_id = $1
+                and user_id <> all($2)
+        ",
+        thread_id,
+        skip
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut notified = Vec::new();
+
+    for user_id in watchers {
+        notified.extend(
+            notify(
+                tx,
+                NewNotification {
+                    user_id,
+                    actor_user_id,
+                    kind: NotificationKind::WatchedComment,
+                    thread_id: Some(thread_id),
+                    comment_id: Some(comment_id),
+                },
+            )
+            .await?,
+        );
+    }
+
+    Ok(notified)
+}
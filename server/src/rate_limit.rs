@@ -0,0 +1,748 @@
+use crate::auth::{AuthUser, Role};
+use crate::automod::Author;
+use crate::error::Error;
+use crate::routes::AppState;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use sqlx::PgExecutor;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Accounts younger than this are held to the restricted limits.
+const NEW_ACCOUNT_AGE_HOURS: i64 = 24;
+/// Accounts with less karma than this are held to the restricted limits.
+const LOW_KARMA: i64 = 10;
+/// How far back to look for an identical post by the same user.
+const DUPLICATE_WINDOW_MINUTES: i32 = 60;
+/// The most keys the limiter tracks for actions, and for requests. Past it,
+/// the keys that no longer affect any limit are dropped, and then those that
+/// would soonest.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Thread,
+    Comment,
+    Vote,
+    Message,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub count: usize,
+    pub per: Duration,
+}
+
+impl Action {
+    /// How long a hit counts against any of the action's limits.
+    fn window(&self) -> Duration {
+        self.user_limit()
+            .per
+            .max(self.restricted_limit().per)
+            .max(self.ip_limit().per)
+    }
+
+    /// The limit for an account in good standing.
+    fn user_limit(&self) -> Limit {
+        match self {
+            Self::Thread => Limit {
+                count: 5,
+                per: HOUR,
+            },
+            Self::Comment => Limit {
+                count: 10,
+                per: MINUTE,
+            },
+            Self::Vote => Limit {
+                count: 30,
+                per: MINUTE,
+            },
+            Self::Message => Limit {
+                count: 20,
+                per: MINUTE,
+            },
+        }
+    }
+
+    /// The limit for a new or low-karma account.
+    fn restricted_limit(&self) -> Limit {
+        match self {
+            Self::Thread => Limit {
+                count: 1,
+                per: HOUR,
+            },
+            Self::Comment => Limit {
+                count: 3,
+                per: MINUTE,
+            },
+            Self::Vote => Limit {
+                count: 10,
+                per: MINUTE,
+            },
+            Self::Message => Limit {
+                count: 5,
+                per: MINUTE,
+            },
+        }
+    }
+
+    /// The limit for all accounts posting from one address.
+    fn ip_limit(&self) -> Limit {
+        match self {
+            Self::Thread => Limit {
+                count: 10,
+                per: HOUR,
+            },
+            Self::Comment => Limit {
+                count: 20,
+                per: MINUTE,
+            },
+            Self::Vote => Limit {
+                count: 60,
+                per: MINUTE,
+            },
+            Self::Message => Limit {
+                count: 40,
+                per: MINUTE,
+            },
+        }
+    }
+}
+
+/// The budget every request to the API draws from, on top of any limit on
+/// the action it takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Budget {
+    AnonymousRead,
+    AuthenticatedRead,
+    Write,
+}
+
+impl Budget {
+    /// How many requests a client can make at once, and how fast it earns them back.
+    fn limit(&self) -> Limit {
+        match self {
+            Self::AnonymousRead => Limit {
+                count: 60,
+                per: MINUTE,
+            },
+            Self::AuthenticatedRead => Limit {
+                count: 300,
+                per: MINUTE,
+            },
+            Self::Write => Limit {
+                count: 60,
+                per: MINUTE,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    User(i64),
+    Ip(IpAddr),
+}
+
+impl Key {
+    /// The key for a client address. IPv6 clients are limited by their /64,
+    /// since a single host is usually handed a whole one to pick from.
+    pub fn ip(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V6(ip) => {
+                let network = u128::from(ip) & !(u128::MAX >> 64);
+                Self::Ip(IpAddr::V6(Ipv6Addr::from(network)))
+            }
+            ip => Self::Ip(ip),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    /// When the bucket is full again, and so the same as no bucket at all.
+    fn full_at(&self, budget: Budget) -> Instant {
+        let limit = budget.limit();
+        let per_token = limit.per.as_secs_f64() / limit.count as f64;
+        let missing = (limit.count as f64 - self.tokens).max(0.0);
+
+        self.updated_at + Duration::from_secs_f64(missing * per_token)
+    }
+}
+
+/// The state of a client's bucket after a request, for the `RateLimit-*` headers.
+#[derive(Debug)]
+struct Quota {
+    limit: usize,
+    remaining: usize,
+    /// Until the bucket is full again.
+    reset: Duration,
+}
+
+/// Sliding-window counters of recent actions and token buckets of recent
+/// requests, kept in memory.
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<(Key, Action), VecDeque<Instant>>>,
+    buckets: Mutex<HashMap<(Key, Budget), Bucket>>,
+    /// Proxies whose `X-Forwarded-For` is believed.
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            trusted_proxies,
+            ..Self::default()
+        }
+    }
+
+    /// The address a request came from: the peer, unless it's a trusted proxy,
+    /// in which case the nearest untrusted address it says it forwarded for.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let forwarded_for: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut client = peer;
+
+        for hop in forwarded_for.into_iter().rev() {
+            if !self.trusted_proxies.contains(&client) {
+                break;
+            }
+
+            match hop.parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+
+        client
+    }
+
+    /// Takes a token from the key's bucket for the budget, or says how long
+    /// until there is one.
+    fn take(&self, key: Key, budget: Budget) -> Result<Quota, Duration> {
+        self.take_at(key, budget, Instant::now())
+    }
+
+    fn take_at(&self, key: Key, budget: Budget, now: Instant) -> Result<Quota, Duration> {
+        let limit = budget.limit();
+        let capacity = limit.count as f64;
+        let per_token = limit.per.as_secs_f64() / capacity;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        prune(&mut buckets, now, |&(_, budget), bucket| {
+            bucket.full_at(budget)
+        });
+
+        let bucket = buckets.entry((key, budget)).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+
+        let earned = now.duration_since(bucket.updated_at).as_secs_f64() / per_token;
+        bucket.tokens = (bucket.tokens + earned).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) * per_token));
+        }
+
+        bucket.tokens -= 1.0;
+
+        Ok(Quota {
+            limit: limit.count,
+            remaining: bucket.tokens as usize,
+            reset: Duration::from_secs_f64((capacity - bucket.tokens) * per_token),
+        })
+    }
+
+    /// Fails if any of the keys is over its limit for the action, with how
+    /// long until all of them would allow it. Nothing is recorded.
+    pub fn peek(&self, action: Action, keys: &[(Key, Limit)]) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        Self::ensure_room(&mut windows, now, action, keys)
+    }
+
+    /// Records an action against every key, unless any of them is over its
+    /// limit, in which case nothing is recorded and the error says how long
+    /// until all of them would allow it.
+    pub fn hit(&self, action: Action, keys: &[(Key, Limit)]) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        prune(&mut windows, now, |&(_, action), hits| {
+            hits.back().map_or(now, |last| *last + action.window())
+        });
+
+        Self::ensure_room(&mut windows, now, action, keys)?;
+
+        for &(key, _) in keys {
+            windows.entry((key, action)).or_default().push_back(now);
+        }
+
+        Ok(())
+    }
+
+    fn ensure_room(
+        windows: &mut HashMap<(Key, Action), VecDeque<Instant>>,
+        now: Instant,
+        action: Action,
+        keys: &[(Key, Limit)],
+    ) -> Result<(), Error> {
+        let mut retry_after = Duration::ZERO;
+
+        for &(key, limit) in keys {
+            let Some(hits) = windows.get_mut(&(key, action)) else {
+                continue;
+            };
+
+            while hits
+                .front()
+                .is_some_and(|first| now.duration_since(*first) >= limit.per)
+            {
+                hits.pop_front();
+            }
+
+            if hits.len() >= limit.count {
+                // The oldest hit that has to expire before there's room again.
+                let oldest = hits[hits.len() - limit.count];
+                retry_after = retry_after.max(limit.per - now.duration_since(oldest));
+            }
+        }
+
+        if retry_after.is_zero() {
+            Ok(())
+        } else {
+            Err(Error::TooManyRequests { retry_after })
+        }
+    }
+}
+
+/// Keeps `map` to at most `MAX_TRACKED_KEYS`, going by when each entry stops
+/// affecting any limit. Once it's over, every expired entry is dropped, and
+/// if that's not enough, those expiring soonest, so it takes a good number of
+/// new keys before it's over again.
+fn prune<K, V>(map: &mut HashMap<K, V>, now: Instant, expires_at: impl Fn(&K, &V) -> Instant)
+where
+    K: Copy + Eq + Hash,
+{
+    if map.len() < MAX_TRACKED_KEYS {
+        return;
+    }
+
+    map.retain(|key, value| expires_at(key, value) > now);
+
+    let keep = MAX_TRACKED_KEYS * 9 / 10;
+
+    if map.len() > keep {
+        let mut expiring: Vec<(Instant, K)> = map
+            .iter()
+            .map(|(key, value)| (expires_at(key, value), *key))
+            .collect();
+        let evict = expiring.len() - keep;
+        expiring.select_nth_unstable_by_key(evict, |&(expires_at, _)| expires_at);
+
+        for (_, key) in &expiring[..evict] {
+            map.remove(key);
+        }
+    }
+}
+
+/// The address of the client making the request, which may be behind a
+/// trusted proxy.
+pub(crate) struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Missing peer address"))?;
+
+        Ok(Self(
+            state.rate_limiter.client_ip(peer.ip(), &parts.headers),
+        ))
+    }
+}
+
+/// Limits every request by the user making it, or by its address if it's
+/// anonymous, and says how much of its budget is left in `RateLimit-*` headers.
+pub(crate) async fn limit_requests<B>(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // An invalid token is rejected by the handler, so it's only anonymous here.
+    let user = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|auth_header| AuthUser::from_authorization(&state, auth_header).ok());
+
+    let budget = match (request.method().is_safe(), &user) {
+        (false, _) => Budget::Write,
+        (true, Some(_)) => Budget::AuthenticatedRead,
+        (true, None) => Budget::AnonymousRead,
+    };
+
+    let key = match user {
+        Some(user) => Key::User(user.id),
+        None => Key::ip(ip),
+    };
+
+    match state.rate_limiter.take(key, budget) {
+        Ok(quota) => {
+            let mut response = next.run(request).await;
+            let headers = response.headers_mut();
+            headers.insert(RATELIMIT_LIMIT, HeaderValue::from(quota.limit));
+            headers.insert(RATELIMIT_REMAINING, HeaderValue::from(quota.remaining));
+            headers.insert(RATELIMIT_RESET, HeaderValue::from(seconds(quota.reset)));
+            response
+        }
+        Err(retry_after) => {
+            let mut response = Error::TooManyRequests { retry_after }.into_response();
+            let headers = response.headers_mut();
+            headers.insert(RATELIMIT_LIMIT, HeaderValue::from(budget.limit().count));
+            headers.insert(RATELIMIT_REMAINING, HeaderValue::from(0));
+            headers.insert(RATELIMIT_RESET, HeaderValue::from(seconds(retry_after)));
+            response
+        }
+    }
+}
+
+/// Whole seconds, rounded up.
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// An action that's within its limits but not yet recorded against them.
+#[must_use = "the action has to be recorded once it's done"]
+pub(crate) struct Permit {
+    action: Action,
+    /// Empty for moderators, who aren't limited.
+    keys: Vec<(Key, Limit)>,
+}
+
+impl Permit {
+    /// Records the action once it's been accepted, so rejected posts don't
+    /// count against the limits. Call it just before committing: it fails if
+    /// concurrent requests used up the limit in the meantime.
+    pub(crate) fn record(self, rate_limiter: &RateLimiter) -> Result<(), Error> {
+        if self.keys.is_empty() {
+            return Ok(());
+        }
+
+        rate_limiter.hit(self.action, &self.keys)
+    }
+}
+
+/// Checks a post, vote or message against the user's and the address's
+/// limits, without recording it. Moderators aren't limited.
+pub(crate) async fn check(
+    state: &AppState,
+    user_id: i64,
+    ip: IpAddr,
+    action: Action,
+) -> Result<Permit, Error> {
+    let author = Author::load(&state.db, user_id).await?;
+
+    if author.role >= Role::Moderator {
+        return Ok(Permit {
+            action,
+            keys: Vec::new(),
+        });
+    }
+
+    let is_restricted = Utc::now() - author.created_at
+        < chrono::Duration::hours(NEW_ACCOUNT_AGE_HOURS)
+        || author.karma < LOW_KARMA;
+
+    let user_limit = if is_restricted {
+        action.restricted_limit()
+    } else {
+        action.user_limit()
+    };
+
+    let keys = vec![
+        (Key::User(user_id), user_limit),
+        (Key::ip(ip), action.ip_limit()),
+    ];
+
+    state.rate_limiter.peek(action, &keys)?;
+
+    Ok(Permit { action, keys })
+}
+
+/// Rejects a thread or comment identical, ignoring case and whitespace, to one
+/// the user posted recently.
+///
+/// Only the user's own posts are compared, since short replies like "Thanks!"
+/// are often posted by different users. The same text posted from several
+/// accounts is left to the per-address limits and the automoderator.
+pub(crate) async fn check_duplicate(
+    db: impl PgExecutor<'_>,
+    user_id: i64,
+    content: &str,
+) -> Result<(), Error> {
+    let is_duplicate = sqlx::query_scalar!(
+        r#"
+            with recent as (
+                select content
+                from threads
+                where user_id = $1
+                    and created_at > now() - make_interval(mins => $3)
+                union all
+                select content
+                from comments
+                where user_id = $1
+                    and created_at > now() - make_interval(mins => $3)
+            )
+            select exists(
+                select *
+                from recent
+                where lower(regexp_replace(trim(content), '\s+', ' ', 'g'))
+                    = lower(regexp_replace(trim($2), '\s+', ' ', 'g'))
+            ) as "exists!"
+        "#,
+        user_id,
+        content,
+        DUPLICATE_WINDOW_MINUTES
+    )
+    .fetch_one(db)
+    .await?;
+
+    if is_duplicate {
+        return Err(Error::unprocessable_entity([(
+            "content",
+            "is a duplicate of one of your recent posts",
+        )]));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.1";
+    const PROXY: &str = "10.0.0.2";
+    const CLIENT: &str = "203.0.113.7";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let limiter = RateLimiter::new(Vec::new());
+
+        assert_eq!(
+            limiter.client_ip(ip(PEER), &forwarded_for(CLIENT)),
+            ip(PEER)
+        );
+    }
+
+    #[test]
+    fn believes_trusted_proxies() {
+        let limiter = RateLimiter::new(vec![ip(PEER), ip(PROXY)]);
+        let headers = forwarded_for(&format!("{}, {}", CLIENT, PROXY));
+
+        assert_eq!(limiter.client_ip(ip(PEER), &headers), ip(CLIENT));
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let limiter = RateLimiter::new(vec![ip(PEER)]);
+        // The client made up the leftmost hop; only the one our proxy added counts.
+        let headers = forwarded_for(&format!("198.51.100.1, {}", CLIENT));
+
+        assert_eq!(limiter.client_ip(ip(PEER), &headers), ip(CLIENT));
+    }
+
+    #[test]
+    fn stops_at_an_unparseable_hop() {
+        let limiter = RateLimiter::new(vec![ip(PEER)]);
+
+        assert_eq!(
+            limiter.client_ip(ip(PEER), &forwarded_for("not-an-ip")),
+            ip(PEER)
+        );
+    }
+
+    #[test]
+    fn reads_every_forwarded_for_header() {
+        let limiter = RateLimiter::new(vec![ip(PEER), ip(PROXY)]);
+        let mut headers = forwarded_for(CLIENT);
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static(PROXY));
+
+        assert_eq!(limiter.client_ip(ip(PEER), &headers), ip(CLIENT));
+    }
+
+    #[test]
+    fn buckets_run_out_and_refill() {
+        let limiter = RateLimiter::default();
+        let key = Key::Ip(ip(CLIENT));
+        let budget = Budget::AnonymousRead;
+        let start = Instant::now();
+
+        for remaining in (0..budget.limit().count).rev() {
+            let quota = limiter.take_at(key, budget, start).unwrap();
+            assert_eq!(quota.remaining, remaining);
+        }
+
+        // A token comes back every second.
+        let retry_after = limiter.take_at(key, budget, start).unwrap_err();
+        assert_eq!(seconds(retry_after), 1);
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.take_at(key, budget, later).is_ok());
+        assert!(limiter.take_at(key, budget, later).is_err());
+
+        let much_later = start + HOUR;
+        let quota = limiter.take_at(key, budget, much_later).unwrap();
+        assert_eq!(quota.remaining, budget.limit().count - 1);
+    }
+
+    #[test]
+    fn buckets_are_per_key_and_budget() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..Budget::Write.limit().count {
+            limiter.take_at(Key::User(1), Budget::Write, now).unwrap();
+        }
+
+        assert!(limiter.take_at(Key::User(1), Budget::Write, now).is_err());
+        assert!(limiter.take_at(Key::User(2), Budget::Write, now).is_ok());
+        assert!(limiter
+            .take_at(Key::User(1), Budget::AuthenticatedRead, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn limits_ipv6_clients_by_their_64() {
+        assert_eq!(
+            Key::ip(ip("2001:db8:1:2:aaaa::1")),
+            Key::ip(ip("2001:db8:1:2:bbbb::2"))
+        );
+        assert_ne!(
+            Key::ip(ip("2001:db8:1:2::1")),
+            Key::ip(ip("2001:db8:1:3::1"))
+        );
+        assert_eq!(Key::ip(ip("::ffff:203.0.113.7")), Key::Ip(ip(CLIENT)));
+    }
+
+    #[test]
+    fn drops_full_buckets_once_tracking_too_many() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for id in 0..MAX_TRACKED_KEYS as i64 {
+            limiter.take_at(Key::User(id), Budget::Write, now).unwrap();
+        }
+
+        // A token comes back every second, which fills every bucket.
+        let later = now + Duration::from_secs(1);
+        limiter
+            .take_at(Key::User(-1), Budget::Write, later)
+            .unwrap();
+
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn caps_the_buckets_tracked() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for id in 0..2 * MAX_TRACKED_KEYS as i64 {
+            limiter.take_at(Key::User(id), Budget::Write, now).unwrap();
+        }
+
+        assert!(limiter.buckets.lock().unwrap().len() <= MAX_TRACKED_KEYS);
+    }
+
+    #[test]
+    fn hits_are_recorded_against_every_key_or_none() {
+        let limiter = RateLimiter::default();
+        let limit = Limit {
+            count: 1,
+            per: MINUTE,
+        };
+        let user = (Key::User(1), limit);
+        let address = (Key::Ip(ip(CLIENT)), limit);
+
+        limiter.hit(Action::Comment, &[address]).unwrap();
+
+        assert!(limiter.hit(Action::Comment, &[user, address]).is_err());
+        // The rejected hit wasn't recorded against the user.
+        assert!(limiter.hit(Action::Comment, &[user]).is_ok());
+        assert!(limiter.hit(Action::Thread, &[user]).is_ok());
+    }
+
+    #[test]
+    fn peeking_records_nothing() {
+        let limiter = RateLimiter::default();
+        let keys = [(
+            Key::User(1),
+            Limit {
+                count: 1,
+                per: MINUTE,
+            },
+        )];
+
+        limiter.peek(Action::Vote, &keys).unwrap();
+        limiter.peek(Action::Vote, &keys).unwrap();
+        limiter.hit(Action::Vote, &keys).unwrap();
+
+        assert!(matches!(
+            limiter.peek(Action::Vote, &keys),
+            Err(Error::TooManyRequests { .. })
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+use super::{AppState, Error, Result};
+use crate::auth::{ModUser, Role};
+use crate::automod::{self, Author, CompiledRule, Post, PostKind, Rule, Verdict};
+use crate::mod_log::{self, ModAction, ModLogEntry};
+use crate::validate::{ValidJson, Validate, Violations};
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// Dry runs are bounded like the longest post, a thread.
+const MAX_TITLE_LENGTH: usize = 300;
+const MAX_CONTENT_LENGTH: usize = 40_000;
+
+#[derive(Serialize)]
+struct RuleInfo {
+    /// `None` for rules from the rules file, which can't be deleted here.
+    id: Option<i64>,
+    #[serde(flatten)]
+    rule: Rule,
+}
+
+#[derive(Deserialize)]
+struct DryRun {
+    kind: PostKind,
+    title: Option<String>,
+    content: String,
+    /// The author to check the account age and karma of. Without one, the post
+    /// is checked as if from a brand new account with no karma.
+    username: Option<String>,
+    /// A rule to try instead of the active rules.
+    rule: Option<Rule>,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/mod/automod/rules", get(get_rules).post(create_rule))
+        .route("/api/mod/automod/rules/:id", delete(delete_rule))
+        .route("/api/mod/automod/dry-run", post(dry_run))
+}
+
+async fn get_rules(
+    _mod_user: ModUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<RuleInfo>>> {
+    let rules = state
+        .automod
+        .rules()
+        .into_iter()
+        .map(|(id, rule)| RuleInfo { id, rule })
+        .collect();
+
+    Ok(Json(rules))
+}
+
+async fn create_rule(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    ValidJson(rule): ValidJson<Rule>,
+) -> Result<Json<RuleInfo>> {
+    // Only stored once it's known to compile.
+    let rule = CompiledRule::new(None, rule)?.rule;
+    let rule_json = serde_json::to_value(&rule).map_err(anyhow::Error::from)?;
+
+    let mut tx = state.db.begin().await?;
+
+    let id = sqlx::query_scalar!(
+        "
+            insert into automod_rules(rule, created_by_user_id)
+            values($1, $2)
+            returning id
+        ",
+        rule_json,
+        mod_user.id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action: ModAction::CreateAutomodRule,
+            target_kind: "automod_rule",
+            target_id: id,
+            target_user_id: None,
+            reason: None,
+            before: None,
+            after: Some(rule_json),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    state.automod.reload(&state.db).await?;
+
+    Ok(Json(RuleInfo { id: Some(id), rule }))
+}
+
+async fn delete_rule(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<()> {
+    let mut tx = state.db.begin().await?;
+
+    let rule = sqlx::query_scalar!(
+        "
+            delete from automod_rules
+            where id = $1
+            returning rule
+        ",
+        id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action: ModAction::DeleteAutomodRule,
+            target_kind: "automod_rule",
+            target_id: id,
+            target_user_id: None,
+            reason: None,
+            before: Some(rule),
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    state.automod.reload(&state.db).await?;
+
+    Ok(())
+}
+
+/// Shows which rules would fire for a post, without posting anything.
+async fn dry_run(
+    _mod_user: ModUser,
+    State(state): State<AppState>,
+    ValidJson(req): ValidJson<DryRun>,
+) -> Result<Json<Verdict>> {
+    let author = match &req.username {
+        Some(username) => {
+            let user_id = sqlx::query_scalar!(
+                "
+                    select id
+                    from users
+                    where username = $1
+                ",
+                username
+            )
+            .fetch_optional(&state.db)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+            Author::load(&state.db, user_id).await?
+        }
+        None => Author {
+            created_at: Utc::now(),
+            karma: 0,
+            role: Role::User,
+        },
+    };
+
+    let post = Post {
+        kind: req.kind,
+        title: req.title.as_deref(),
+        content: &req.content,
+    };
+
+    let verdict = match req.rule {
+        Some(rule) => automod::evaluate([&CompiledRule::new(None, rule)?], &post, &author),
+        None => state.automod.evaluate(&post, &author),
+    };
+
+    Ok(Json(verdict))
+}
+
+impl Validate for DryRun {
+    fn check(&self, violations: &mut Violations) {
+        if let Some(title) = &self.title {
+            violations.field("title", title).max_chars(MAX_TITLE_LENGTH);
+        }
+
+        violations
+            .field("content", &self.content)
+            .max_chars(MAX_CONTENT_LENGTH);
+
+        if let Some(rule) = &self.rule {
+            rule.check(violations);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use super::{AppState, Error, Pagination, Result};
+use crate::auth::{AnyAuthUser, ModUser, Role};
+use crate::mod_log::{self, ModAction, ModLogEntry};
+use crate::validate::{ValidJson, Validate, Violations};
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Duration, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+const MAX_REASON_LENGTH: usize = 1000;
+const MAX_APPEAL_LENGTH: usize = 2000;
+/// Ten years. Anything longer should be a permanent ban.
+pub(crate) const MAX_DURATION_HOURS: i64 = 10 * 365 * 24;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BanKind {
+    /// Blocks writes until it expires.
+    Suspension,
+    /// Blocks writes permanently.
+    Ban,
+    /// Hides the user's new threads and comments from everyone but themselves.
+    Shadowban,
+}
+
+impl BanKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Suspension => "suspension",
+            Self::Ban => "ban",
+            Self::Shadowban => "shadowban",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewBan {
+    kind: BanKind,
+    reason: String,
+    /// Required for suspensions, optional for shadowbans and not allowed for bans.
+    duration_hours: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct NewAppeal {
+    message: String,
+}
+
+/// A ban as shown to moderators.
+#[derive(Serialize)]
+struct Ban {
+    id: i64,
+    username: String,
+    kind: String,
+    reason: String,
+    expires_at: Option<DateTime<Local>>,
+    created_by: String,
+    created_at: DateTime<Local>,
+    lifted_at: Option<DateTime<Local>>,
+    appeal: Option<String>,
+    appealed_at: Option<DateTime<Local>>,
+}
+
+/// A ban as shown to the banned user.
+#[derive(Serialize)]
+struct BanStatus {
+    kind: String,
+    reason: String,
+    expires_at: Option<DateTime<Local>>,
+    created_at: DateTime<Local>,
+    appeal: Option<String>,
+    appealed_at: Option<DateTime<Local>>,
+}
+
+#[derive(Serialize)]
+struct BansLifted {
+    count: u64,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/users/ban", get(get_own_ban))
+        .route("/api/users/ban/appeal", post(appeal_ban))
+        .route(
+            "/api/mod/users/:username/ban",
+            post(ban_user).delete(lift_bans),
+        )
+        .route("/api/mod/users/:username/bans", get(get_bans))
+        .route("/api/mod/appeals", get(get_appeals))
+}
+
+/// The caller's active suspension or ban, if any. Shadowbans aren't disclosed.
+async fn get_own_ban(
+    auth_user: AnyAuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<Option<BanStatus>>> {
+    let ban = sqlx::query_as!(
+        BanStatus,
+        r#"
+            select
+                kind as "kind!",
+                reason as "reason!",
+                expires_at as "expires_at: DateTime<Local>",
+                created_at as "created_at!: DateTime<Local>",
+                appeal,
+                appealed_at as "appealed_at: DateTime<Local>"
+            from active_bans
+            where user_id = $1
+                and kind <> 'shadowban'
+            order by expires_at desc nulls first
+            limit 1
+        "#,
+        auth_user.id
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    Ok(Json(ban))
+}
+
+/// Lets a suspended or banned user submit a single appeal against their ban.
+async fn appeal_ban(
+    auth_user: AnyAuthUser,
+    State(state): State<AppState>,
+    ValidJson(req): ValidJson<NewAppeal>,
+) -> Result<Json<BanStatus>> {
+    let message = req.message.trim();
+
+    let ban = sqlx::query!(
+        r#"
+            select
+                id as "id!",
+                appeal
+            from active_bans
+            where user_id = $1
+                and kind <> 'shadowban'
+            order by expires_at desc nulls first
+            limit 1
+        "#,
+        auth_user.id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let already_submitted =
+        || Error::unprocessable_entity([("appeal", "has already been submitted")]);
+
+    if ban.appeal.is_some() {
+        return Err(already_submitted());
+    }
+
+    // Guarded again here so concurrent appeals can't overwrite each other.
+    let ban = sqlx::query_as!(
+        BanStatus,
+        r#"
+            update bans
+            set appeal = $2,
+                appealed_at = now()
+            where id = $1
+                and appeal is null
+            returning
+                kind,
+                reason,
+                expires_at as "expires_at: DateTime<Local>",
+                created_at as "created_at: DateTime<Local>",
+                appeal,
+                appealed_at as "appealed_at: DateTime<Local>"
+        "#,
+        ban.id,
+        message
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(already_submitted)?;
+
+    Ok(Json(ban))
+}
+
+async fn ban_user(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    ValidJson(req): ValidJson<NewBan>,
+) -> Result<Json<Ban>> {
+    let reason = req.reason.trim();
+    let expires_at = req
+        .duration_hours
+        .map(|hours| Utc::now() + Duration::hours(hours));
+
+    let mut tx = state.db.begin().await?;
+
+    let target = sqlx::query!(
+        "
+            select id, role
+            from users
+            where username = $1
+        ",
+        username
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    // Moderators can't ban each other; only admins can ban moderators.
+    if Role::from_db(&target.role) >= mod_user.role {
+        return Err(Error::Forbidden);
+    }
+
+    let ban = sqlx::query_as!(
+        Ban,
+        r#"
+            with ban as (
+                insert into bans(user_id, kind, reason, expires_at, created_by_user_id)
+                values($1, $2, $3, $4, $5)
+                returning *
+            )
+            select
+                a.id,
+                b.username,
+                a.kind,
+                a.reason,
+                a.expires_at as "expires_at: DateTime<Local>",
+                c.username as created_by,
+                a.created_at as "created_at: DateTime<Local>",
+                a.lifted_at as "lifted_at: DateTime<Local>",
+                a.appeal,
+                a.appealed_at as "appealed_at: DateTime<Local>"
+            from ban a
+            join users b on a.user_id = b.id
+            join users c on a.created_by_user_id = c.id
+        "#,
+        target.id,
+        req.kind.as_str(),
+        reason,
+        expires_at,
+        mod_user.id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action: ModAction::Ban,
+            target_kind: "user",
+            target_id: target.id,
+            target_user_id: Some(target.id),
+            reason: Some(reason),
+            before: None,
+            after: Some(serde_json::json!({
+                "ban_id": ban.id,
+                "kind": ban.kind,
+                "expires_at": ban.expires_at,
+            })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ban))
+}
+
+async fn lift_bans(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<BansLifted>> {
+    let mut tx = state.db.begin().await?;
+
+    let user_id = sqlx::query_scalar!(
+        "
+            select id
+            from users
+            where username = $1
+        ",
+        username
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let lifted = sqlx::query_scalar!(
+        "
+            update bans
+            set lifted_at = now(),
+                lifted_by_user_id = $2
+            where id in (
+                select id
+                from active_bans
+                where user_id = $1
+            )
+            returning id
+        ",
+        user_id,
+        mod_user.id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    if !lifted.is_empty() {
+        mod_log::record(
+            &mut tx,
+            ModLogEntry {
+                actor_user_id: mod_user.id,
+                action: ModAction::LiftBans,
+                target_kind: "user",
+                target_id: user_id,
+                target_user_id: Some(user_id),
+                reason: None,
+                before: Some(serde_json::json!({ "ban_ids": lifted })),
+                after: None,
+            },
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(BansLifted {
+        count: lifted.len() as u64,
+    }))
+}
+
+async fn get_bans(
+    _mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Vec<Ban>>> {
+    let bans = sqlx::query_as!(
+        Ban,
+        r#"
+            select
+                a.id,
+                b.username,
+                a.kind,
+                a.reason,
+                a.expires_at as "expires_at: DateTime<Local>",
+                c.username as created_by,
+                a.created_at as "created_at: DateTime<Local>",
+                a.lifted_at as "lifted_at: DateTime<Local>",
+                a.appeal,
+                a.appealed_at as "appealed_at: DateTime<Local>"
+            from bans a
+            join users b on a.user_id = b.id
+            join users c on a.created_by_user_id = c.id
+            where b.username = $1
+            order by a.created_at desc
+        "#,
+        username
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(bans))
+}
+
+/// Appeals against bans that are still in effect, oldest first.
+async fn get_appeals(
+    _mod_user: ModUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Ban>>> {
+    let appeals = sqlx::query_as!(
+        Ban,
+        r#"
+            select
+                a.id as "id!",
+                b.username,
+                a.kind as "kind!",
+                a.reason as "reason!",
+                a.expires_at as "expires_at: DateTime<Local>",
+                c.username as created_by,
+                a.created_at as "created_at!: DateTime<Local>",
+                a.lifted_at as "lifted_at: DateTime<Local>",
+                a.appeal,
+                a.appealed_at as "appealed_at: DateTime<Local>"
+            from active_bans a
+            join users b on a.user_id = b.id
+            join users c on a.created_by_user_id = c.id
+            where a.appeal is not null
+            order by a.appealed_at
+            limit $1
+            offset $2
+        "#,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(appeals))
+}
+
+impl Validate for NewBan {
+    fn check(&self, violations: &mut Violations) {
+        violations
+            .field("reason", self.reason.trim())
+            .not_blank()
+            .max_chars(MAX_REASON_LENGTH);
+
+        match (self.kind, self.duration_hours) {
+            (_, Some(hours)) if hours <= 0 => violations.add("duration_hours", "must be positive"),
+            (_, Some(hours)) if hours > MAX_DURATION_HOURS => violations.add(
+                "duration_hours",
+                format!("must be at most {}", MAX_DURATION_HOURS),
+            ),
+            (BanKind::Ban, Some(_)) => {
+                violations.add("duration_hours", "can't be set for a permanent ban")
+            }
+            (BanKind::Suspension, None) => {
+                violations.add("duration_hours", "is required for a suspension")
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Validate for NewAppeal {
+    fn check(&self, violations: &mut Violations) {
+        violations
+            .field("message", self.message.trim())
+            .not_blank()
+            .max_chars(MAX_APPEAL_LENGTH);
+    }
+}
@@ -1,5 +1,13 @@
-use super::{AppState, Result};
-use crate::auth::{AuthUser, MaybeAuthUser};
+use super::stream::{Event, Topic};
+use super::threads::VoteCount;
+use super::{AppState, Error, Result};
+use crate::auth::{AuthUser, MaybeAuthUser, Role};
+use crate::automod::{self, Post, PostKind};
+use crate::mentions;
+use crate::mod_log::{self, ModAction, ModLogEntry};
+use crate::notifications::{self, notify, NewNotification, NotificationKind};
+use crate::rate_limit::{self, Action, ClientIp};
+use crate::validate::{ValidJson, Validate, Violations};
 use axum::{
     extract::{Path, State},
     routing::{get, post},
@@ -7,27 +15,40 @@ use axum::{
 };
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+const MAX_CONTENT_LENGTH: usize = 10_000;
 
 #[derive(Deserialize)]
 struct NewComment {
     content: String,
 }
 
+#[derive(Deserialize)]
+struct CommentEdit {
+    content: String,
+    /// Recorded in the moderation log when a moderator edits someone else's comment.
+    reason: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Comment {
+pub(crate) struct Comment {
     id: i64,
     pid: Option<i64>,
     author_id: i64,
     username: String,
     content: String,
     created_at: DateTime<Local>,
+    edited_at: Option<DateTime<Local>>,
+    /// The viewer has blocked the author, so the content is hidden.
+    is_blocked: bool,
+    /// Held for review by the automoderator; only the author sees it until approved.
+    is_held: bool,
     is_voted: bool,
+    is_saved: bool,
     vote_count: i64,
-}
-
-#[derive(Serialize, Deserialize)]
-struct VoteCount {
-    count: i64,
+    /// The users the content mentions, which the client links to.
+    mentions: Vec<String>,
 }
 
 pub(crate) fn router() -> Router<AppState> {
@@ -38,13 +59,19 @@ pub(crate) fn router() -> Router<AppState> {
         )
         .route(
             "/api/threads/:slug/comments/:id",
-            post(create_nested_comment).get(get_comment),
+            post(create_nested_comment)
+                .get(get_comment)
+                .put(edit_comment),
         )
         .route("/api/threads/:slug/comments/:id/vote", post(vote_comment))
         .route(
             "/api/threads/:slug/comments/:id/unvote",
             post(unvote_comment),
         )
+        .route(
+            "/api/threads/:slug/comments/:id/save",
+            post(save_comment).delete(unsave_comment),
+        )
         .route(
             "/api/threads/:slug/comments/:id/children",
             get(get_child_comments),
@@ -54,11 +81,17 @@ pub(crate) fn router() -> Router<AppState> {
 async fn vote_comment(
     auth_user: AuthUser,
     State(state): State<AppState>,
-    Path((_slug, id)): Path<(String, String)>,
+    ClientIp(ip): ClientIp,
+    Path((slug, id)): Path<(String, String)>,
 ) -> Result<Json<VoteCount>> {
-    let id_actual = i64::from_str_radix(&id, 36).unwrap();
+    rate_limit::check(&state, auth_user.id, ip, Action::Vote)
+        .await?
+        .record(&state.rate_limiter)?;
 
-    sqlx::query!(
+    let comment = votable_comment(&state, &slug, &id, auth_user.id).await?;
+    let id_actual = comment.id;
+
+    let inserted = sqlx::query!(
         "
             insert into comment_votes(comment_id, user_id)
             values($1, $2)
@@ -68,7 +101,12 @@ async fn vote_comment(
         auth_user.id
     )
     .execute(&state.db)
-    .await?;
+    .await?
+    .rows_affected();
+
+    if inserted > 0 {
+        metrics::increment_counter!("votes_created_total", "kind" => "comment");
+    }
 
     let count = sqlx::query_as!(
         VoteCount,
@@ -82,15 +120,31 @@ async fn vote_comment(
     .fetch_one(&state.db)
     .await?;
 
+    if comment.is_visible {
+        state.events.publish(
+            Topic::Thread(slug),
+            Event::CommentVotes {
+                id: id_actual,
+                votes: count.clone(),
+            },
+        );
+    }
+
     Ok(Json(count))
 }
 
 async fn unvote_comment(
     auth_user: AuthUser,
     State(state): State<AppState>,
-    Path((_slug, id)): Path<(String, String)>,
+    ClientIp(ip): ClientIp,
+    Path((slug, id)): Path<(String, String)>,
 ) -> Result<Json<VoteCount>> {
-    let id_actual = i64::from_str_radix(&id, 36).unwrap();
+    rate_limit::check(&state, auth_user.id, ip, Action::Vote)
+        .await?
+        .record(&state.rate_limiter)?;
+
+    let comment = votable_comment(&state, &slug, &id, auth_user.id).await?;
+    let id_actual = comment.id;
 
     sqlx::query!(
         "
@@ -116,35 +170,198 @@ async fn unvote_comment(
     .fetch_one(&state.db)
     .await?;
 
+    if comment.is_visible {
+        state.events.publish(
+            Topic::Thread(slug),
+            Event::CommentVotes {
+                id: id_actual,
+                votes: count.clone(),
+            },
+        );
+    }
+
     Ok(Json(count))
 }
 
+async fn save_comment(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path((slug, id)): Path<(String, String)>,
+) -> Result<()> {
+    let id_actual = i64::from_str_radix(&id, 36).map_err(|_| Error::NotFound)?;
+
+    let comment_id = sqlx::query_scalar!(
+        "
+            select a.id
+            from comments a
+            join threads b on a.thread_id = b.id
+            where a.id = $1
+                and b.slug = $2
+                and a.removed_at is null
+                and b.removed_at is null
+        ",
+        id_actual,
+        slug
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    sqlx::query!(
+        "
+            insert into comment_saves(comment_id, user_id)
+            values($1, $2)
+            on conflict do nothing
+        ",
+        comment_id,
+        auth_user.id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+async fn unsave_comment(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path((_slug, id)): Path<(String, String)>,
+) -> Result<()> {
+    let id_actual = i64::from_str_radix(&id, 36).map_err(|_| Error::NotFound)?;
+
+    sqlx::query!(
+        "
+            delete from comment_saves
+            where comment_id = $1
+                and user_id = $2
+        ",
+        id_actual,
+        auth_user.id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
 async fn create_nested_comment(
     auth_user: AuthUser,
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Path((slug, pid)): Path<(String, String)>,
-    Json(req): Json<NewComment>,
+    ValidJson(req): ValidJson<NewComment>,
 ) -> Result<Json<Comment>> {
-    let id = sqlx::query_scalar!(
+    let permit = rate_limit::check(&state, auth_user.id, ip, Action::Comment).await?;
+    rate_limit::check_duplicate(&state.db, auth_user.id, &req.content).await?;
+
+    let pid = i64::from_str_radix(&pid, 36).map_err(|_| Error::NotFound)?;
+    let mut tx = state.db.begin().await?;
+
+    ensure_unlocked(&mut tx, &slug).await?;
+    ensure_not_blocked(&mut tx, &slug, Some(pid), auth_user.id).await?;
+
+    let verdict = state
+        .automod
+        .check(
+            &mut tx,
+            auth_user.id,
+            Post {
+                kind: PostKind::Comment,
+                title: None,
+                content: &req.content,
+            },
+        )
+        .await?;
+
+    let inserted = sqlx::query!(
         r#"
-            insert into comments(thread_id, user_id, content, pid)
+            insert into comments(thread_id, user_id, content, pid, shadowed, held_at)
             select 
                 id as thread_id,
                 $2,
                 $3,
-                $4
+                $4,
+                exists(
+                    select *
+                    from active_bans
+                    where user_id = $2
+                        and kind = 'shadowban'
+                ),
+                case when $5 then now() end
             from threads
             where slug = $1
-            returning id
+            returning id, thread_id, shadowed, held_at is not null as "is_held!"
         "#,
         slug,
         auth_user.id,
         req.content,
-        i64::from_str_radix(&pid, 36).unwrap()
+        pid,
+        verdict.is_held()
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    automod::report(&mut tx, PostKind::Comment, inserted.id, &verdict).await?;
+
+    // Shadowbanned users' comments and held comments shouldn't reach anyone else.
+    let is_visible = !inserted.shadowed && !inserted.is_held;
+
+    let mut notified = mentions::record_mentions(
+        &mut tx,
+        auth_user.id,
+        inserted.thread_id,
+        Some(inserted.id),
+        &req.content,
+        is_visible,
     )
-    .fetch_one(&state.db)
     .await?;
 
+    if is_visible {
+        let parent_author_id = sqlx::query_scalar!(
+            "
+                select user_id
+                from comments
+                where id = $1
+            ",
+            pid
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        notified.extend(
+            notify(
+                &mut tx,
+                NewNotification {
+                    user_id: parent_author_id,
+                    actor_user_id: auth_user.id,
+                    kind: NotificationKind::Reply,
+                    thread_id: Some(inserted.thread_id),
+                    comment_id: Some(inserted.id),
+                },
+            )
+            .await?,
+        );
+
+        let skip = notified
+            .iter()
+            .map(|n| n.user_id)
+            .chain([parent_author_id])
+            .collect::<Vec<_>>();
+
+        notified.extend(
+            notifications::notify_watchers(
+                &mut tx,
+                auth_user.id,
+                inserted.thread_id,
+                inserted.id,
+                &skip,
+            )
+            .await?,
+        );
+    }
+
+    notifications::auto_watch(&mut tx, auth_user.id, inserted.thread_id).await?;
+
     let comment = sqlx::query_as!(
         Comment,
         r#"
@@ -155,44 +372,159 @@ async fn create_nested_comment(
                 username,
                 content,
                 a.created_at as "created_at: DateTime<Local>",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                false as "is_blocked!",
                 false as "is_voted!",
-                0::bigint as "vote_count!"
+                false as "is_saved!",
+                0::bigint as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.comment_id = a.id
+                    order by mentions.id
+                ) as "mentions!"
             from comments a
             join users b on a.user_id = b.id
             where a.id = $1
         "#,
-        id
+        inserted.id
     )
-    .fetch_one(&state.db)
+    .fetch_one(&mut tx)
     .await?;
 
+    permit.record(&state.rate_limiter)?;
+    tx.commit().await?;
+
+    metrics::increment_counter!("comments_created_total");
+
+    state.events.notify(notified);
+
+    if is_visible {
+        state
+            .events
+            .publish(Topic::Thread(slug), Event::Comment(comment.clone()));
+    }
+
     Ok(Json(comment))
 }
 
 async fn create_top_level_comment(
     auth_user: AuthUser,
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Path(slug): Path<String>,
-    Json(req): Json<NewComment>,
+    ValidJson(req): ValidJson<NewComment>,
 ) -> Result<Json<Comment>> {
-    let id = sqlx::query_scalar!(
+    let permit = rate_limit::check(&state, auth_user.id, ip, Action::Comment).await?;
+    rate_limit::check_duplicate(&state.db, auth_user.id, &req.content).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    ensure_unlocked(&mut tx, &slug).await?;
+    ensure_not_blocked(&mut tx, &slug, None, auth_user.id).await?;
+
+    let verdict = state
+        .automod
+        .check(
+            &mut tx,
+            auth_user.id,
+            Post {
+                kind: PostKind::Comment,
+                title: None,
+                content: &req.content,
+            },
+        )
+        .await?;
+
+    let inserted = sqlx::query!(
         r#"
-            insert into comments(thread_id, user_id, content)
+            insert into comments(thread_id, user_id, content, shadowed, held_at)
             select 
                 id as thread_id,
                 $2,
-                $3
+                $3,
+                exists(
+                    select *
+                    from active_bans
+                    where user_id = $2
+                        and kind = 'shadowban'
+                ),
+                case when $4 then now() end
             from threads
             where slug = $1
-            returning id;
+            returning id, thread_id, shadowed, held_at is not null as "is_held!";
         "#,
         slug,
         auth_user.id,
-        req.content
+        req.content,
+        verdict.is_held()
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    automod::report(&mut tx, PostKind::Comment, inserted.id, &verdict).await?;
+
+    // Shadowbanned users' comments and held comments shouldn't reach anyone else.
+    let is_visible = !inserted.shadowed && !inserted.is_held;
+
+    let mut notified = mentions::record_mentions(
+        &mut tx,
+        auth_user.id,
+        inserted.thread_id,
+        Some(inserted.id),
+        &req.content,
+        is_visible,
     )
-    .fetch_one(&state.db)
     .await?;
 
+    if is_visible {
+        let thread_author_id = sqlx::query_scalar!(
+            "
+                select user_id
+                from threads
+                where id = $1
+            ",
+            inserted.thread_id
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        notified.extend(
+            notify(
+                &mut tx,
+                NewNotification {
+                    user_id: thread_author_id,
+                    actor_user_id: auth_user.id,
+                    kind: NotificationKind::ThreadComment,
+                    thread_id: Some(inserted.thread_id),
+                    comment_id: Some(inserted.id),
+                },
+            )
+            .await?,
+        );
+
+        let skip = notified
+            .iter()
+            .map(|n| n.user_id)
+            .chain([thread_author_id])
+            .collect::<Vec<_>>();
+
+        notified.extend(
+            notifications::notify_watchers(
+                &mut tx,
+                auth_user.id,
+                inserted.thread_id,
+                inserted.id,
+                &skip,
+            )
+            .await?,
+        );
+    }
+
+    notifications::auto_watch(&mut tx, auth_user.id, inserted.thread_id).await?;
+
     let comment = sqlx::query_as!(
         Comment,
         r#"
@@ -203,17 +535,208 @@ async fn create_top_level_comment(
                 username,
                 content,
                 a.created_at as "created_at: DateTime<Local>",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                false as "is_blocked!",
                 false as "is_voted!",
-                0::bigint as "vote_count!"
+                false as "is_saved!",
+                0::bigint as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.comment_id = a.id
+                    order by mentions.id
+                ) as "mentions!"
             from comments a
             join users b on a.user_id = b.id
             where a.id = $1
         "#,
-        id
+        inserted.id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    permit.record(&state.rate_limiter)?;
+    tx.commit().await?;
+
+    metrics::increment_counter!("comments_created_total");
+
+    state.events.notify(notified);
+
+    if is_visible {
+        state
+            .events
+            .publish(Topic::Thread(slug), Event::Comment(comment.clone()));
+    }
+
+    Ok(Json(comment))
+}
+
+/// Edits a comment. Authors can edit their own comments unless the thread is
+/// locked; moderators can edit any comment, which is recorded in the
+/// moderation log.
+async fn edit_comment(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path((slug, id)): Path<(String, String)>,
+    ValidJson(req): ValidJson<CommentEdit>,
+) -> Result<Json<Comment>> {
+    let id = i64::from_str_radix(&id, 36).map_err(|_| Error::NotFound)?;
+
+    let mut tx = state.db.begin().await?;
+
+    let existing = sqlx::query!(
+        r#"
+            select
+                a.user_id,
+                a.thread_id,
+                a.shadowed,
+                b.locked_at is not null as "is_locked!"
+            from comments a
+            join threads b on a.thread_id = b.id
+            where a.id = $1
+                and b.slug = $2
+                and a.removed_at is null
+            for update of a
+        "#,
+        id,
+        slug
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let is_author = existing.user_id == auth_user.id;
+
+    if (!is_author || existing.is_locked) && auth_user.role(&state).await? < Role::Moderator {
+        return Err(Error::Forbidden);
+    }
+
+    let before = if is_author {
+        None
+    } else {
+        mod_log::comment_snapshot(&mut tx, id).await?
+    };
+
+    let verdict = state
+        .automod
+        .check(
+            &mut tx,
+            auth_user.id,
+            Post {
+                kind: PostKind::Comment,
+                title: None,
+                content: &req.content,
+            },
+        )
+        .await?;
+
+    let is_held = sqlx::query_scalar!(
+        r#"
+            update comments
+            set content = $2,
+                edited_at = now(),
+                held_at = case when $3 then coalesce(held_at, now()) else held_at end
+            where id = $1
+            returning held_at is not null as "is_held!"
+        "#,
+        id,
+        req.content,
+        verdict.is_held()
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    automod::report(&mut tx, PostKind::Comment, id, &verdict).await?;
+
+    let is_visible = !existing.shadowed && !is_held;
+
+    // Mentions added by a moderator's edit aren't the author's to send.
+    let notified = mentions::record_mentions(
+        &mut tx,
+        existing.user_id,
+        existing.thread_id,
+        Some(id),
+        &req.content,
+        is_author && is_visible,
     )
-    .fetch_one(&state.db)
     .await?;
 
+    if let Some(before) = before {
+        let after = mod_log::comment_snapshot(&mut tx, id)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        mod_log::record(
+            &mut tx,
+            ModLogEntry {
+                actor_user_id: auth_user.id,
+                action: ModAction::EditComment,
+                target_kind: "comment",
+                target_id: id,
+                target_user_id: Some(before.user_id),
+                reason: req.reason.as_deref(),
+                before: Some(before.value),
+                after: Some(after.value),
+            },
+        )
+        .await?;
+    }
+
+    let comment = sqlx::query_as!(
+        Comment,
+        r#"
+            select
+                a.id,
+                pid,
+                user_id as author_id,
+                username,
+                content,
+                a.created_at as "created_at: DateTime<Local>",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                false as "is_blocked!",
+                exists(
+                    select *
+                    from comment_votes
+                    where comment_id = a.id
+                        and user_id = $2
+                ) as "is_voted!",
+                exists(
+                    select *
+                    from comment_saves
+                    where comment_id = a.id
+                        and user_id = $2
+                ) as "is_saved!",
+                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.comment_id = a.id
+                    order by mentions.id
+                ) as "mentions!"
+            from comments a
+            join users b on a.user_id = b.id
+            where a.id = $1
+        "#,
+        id,
+        auth_user.id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    state.events.notify(notified);
+
+    if is_visible {
+        state
+            .events
+            .publish(Topic::Thread(slug), Event::Comment(comment.clone()));
+    }
+
     Ok(Json(comment))
 }
 
@@ -222,7 +745,7 @@ async fn get_comment(
     State(state): State<AppState>,
     Path((slug, id)): Path<(String, String)>,
 ) -> Result<Json<Comment>> {
-    let id = i64::from_str_radix(&id, 36).unwrap();
+    let id = i64::from_str_radix(&id, 36).map_err(|_| Error::NotFound)?;
     let thread_id = sqlx::query_scalar!(
         "
             select id
@@ -242,8 +765,15 @@ async fn get_comment(
                 pid,
                 user_id as author_id,
                 username,
-                content,
+                case
+                    when a.removed_at is not null then '[removed]'
+                    when c.blocked_user_id is not null then '[blocked]'
+                    else content
+                end as "content!",
                 a.created_at as "created_at: DateTime<Local>",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                c.blocked_user_id is not null as "is_blocked!",
                 exists(
                     select *
                     from comment_votes
@@ -251,28 +781,46 @@ async fn get_comment(
                         and comment_id = a.id
                         and user_id = $3
                 ) as "is_voted!",
-                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!"
+                exists(
+                    select *
+                    from comment_saves
+                    where thread_id = $1
+                        and comment_id = a.id
+                        and user_id = $3
+                ) as "is_saved!",
+                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.comment_id = a.id
+                    order by mentions.id
+                ) as "mentions!"
             from comments a
             join users b on a.user_id = b.id
+            left join blocks c on c.blocker_user_id = $3 and c.blocked_user_id = a.user_id
             where a.thread_id = $1
                 and a.id = $2
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $3)
             order by a.created_at desc
         "#,
         thread_id,
         id,
         auth_user.id()
     )
-    .fetch_one(&state.db)
-    .await?;
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
 
     Ok(Json(comment))
 }
 
 async fn get_child_comments(
+    auth_user: MaybeAuthUser,
     State(state): State<AppState>,
     Path((slug, id)): Path<(String, String)>,
 ) -> Result<Json<Vec<Comment>>> {
-    let id = i64::from_str_radix(&id, 36).unwrap();
+    let id = i64::from_str_radix(&id, 36).map_err(|_| Error::NotFound)?;
     let thread_id = sqlx::query_scalar!(
         "
             select id
@@ -284,6 +832,21 @@ async fn get_child_comments(
     .fetch_one(&state.db)
     .await?;
 
+    if let Some(user_id) = auth_user.id() {
+        sqlx::query!(
+            "
+                update thread_watches
+                set last_read_at = now()
+                where user_id = $1
+                    and thread_id = $2
+            ",
+            user_id,
+            thread_id
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
     let comments = sqlx::query_as!(
         Comment,
         r#"
@@ -292,23 +855,48 @@ async fn get_child_comments(
                 pid,
                 user_id as author_id,
                 username,
-                content,
+                case
+                    when a.removed_at is not null then '[removed]'
+                    when c.blocked_user_id is not null then '[blocked]'
+                    else content
+                end as "content!",
                 a.created_at as "created_at: DateTime<Local>",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                c.blocked_user_id is not null as "is_blocked!",
                 exists(
                     select *
                     from comment_votes
                     where thread_id = $1
-                    and comment_id = a.id
+                        and comment_id = a.id
+                        and user_id = $3
                 ) as "is_voted!",
-                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!"
+                exists(
+                    select *
+                    from comment_saves
+                    where thread_id = $1
+                        and comment_id = a.id
+                        and user_id = $3
+                ) as "is_saved!",
+                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.comment_id = a.id
+                    order by mentions.id
+                ) as "mentions!"
             from comments a
             join users b on a.user_id = b.id
+            left join blocks c on c.blocker_user_id = $3 and c.blocked_user_id = a.user_id
             where a.thread_id = $1
                 and a.pid = $2
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $3)
             order by a.created_at desc
         "#,
         thread_id,
-        id
+        id,
+        auth_user.id()
     )
     .fetch_all(&state.db)
     .await?;
@@ -332,6 +920,21 @@ async fn get_comments(
     .fetch_one(&state.db)
     .await?;
 
+    if let Some(user_id) = auth_user.id() {
+        sqlx::query!(
+            "
+                update thread_watches
+                set last_read_at = now()
+                where user_id = $1
+                    and thread_id = $2
+            ",
+            user_id,
+            thread_id
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
     let comments = sqlx::query_as!(
         Comment,
         r#"
@@ -340,8 +943,15 @@ async fn get_comments(
                 pid,
                 user_id as author_id,
                 username,
-                content,
+                case
+                    when a.removed_at is not null then '[removed]'
+                    when c.blocked_user_id is not null then '[blocked]'
+                    else content
+                end as "content!",
                 a.created_at as "created_at: DateTime<Local>",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                c.blocked_user_id is not null as "is_blocked!",
                 exists(
                     select *
                     from comment_votes
@@ -349,10 +959,26 @@ async fn get_comments(
                         and comment_id = a.id
                         and user_id = $2
                 ) as "is_voted!",
-                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!"
+                exists(
+                    select *
+                    from comment_saves
+                    where thread_id = $1
+                        and comment_id = a.id
+                        and user_id = $2
+                ) as "is_saved!",
+                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.comment_id = a.id
+                    order by mentions.id
+                ) as "mentions!"
             from comments a
             join users b on a.user_id = b.id
+            left join blocks c on c.blocker_user_id = $2 and c.blocked_user_id = a.user_id
             where a.thread_id = $1
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $2)
             order by a.created_at desc
         "#,
         thread_id,
@@ -363,3 +989,117 @@ async fn get_comments(
 
     Ok(Json(comments))
 }
+
+/// Rejects new comments on locked threads.
+struct VotableComment {
+    id: i64,
+    /// Visible to everyone, rather than only to its author.
+    is_visible: bool,
+}
+
+/// A comment in the thread that the user can see and so vote on.
+async fn votable_comment(
+    state: &AppState,
+    slug: &str,
+    id: &str,
+    user_id: i64,
+) -> Result<VotableComment> {
+    let id = i64::from_str_radix(id, 36).map_err(|_| Error::NotFound)?;
+
+    sqlx::query_as!(
+        VotableComment,
+        r#"
+            select
+                a.id,
+                not a.shadowed and a.held_at is null
+                    and not b.shadowed and b.held_at is null as "is_visible!"
+            from comments a
+            join threads b on a.thread_id = b.id
+            where a.id = $1
+                and b.slug = $2
+                and a.removed_at is null
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $3)
+                and b.removed_at is null
+                and ((not b.shadowed and b.held_at is null) or b.user_id = $3)
+        "#,
+        id,
+        slug,
+        user_id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+async fn ensure_unlocked(tx: &mut Transaction<'_, Postgres>, slug: &str) -> Result<()> {
+    let is_locked = sqlx::query_scalar!(
+        r#"
+            select locked_at is not null as "is_locked!"
+            from threads
+            where slug = $1
+        "#,
+        slug
+    )
+    .fetch_optional(tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if is_locked {
+        return Err(Error::unprocessable_entity([("thread", "is locked")]));
+    }
+
+    Ok(())
+}
+
+/// Rejects replies to a thread (`pid` of `None`) or comment whose author has
+/// blocked the replier.
+async fn ensure_not_blocked(
+    tx: &mut Transaction<'_, Postgres>,
+    slug: &str,
+    pid: Option<i64>,
+    user_id: i64,
+) -> Result<()> {
+    let is_blocked = sqlx::query_scalar!(
+        r#"
+            select exists(
+                select *
+                from blocks
+                where blocked_user_id = $3
+                    and blocker_user_id = coalesce(
+                        (select user_id from comments where id = $2),
+                        (select user_id from threads where slug = $1)
+                    )
+            ) as "is_blocked!"
+        "#,
+        slug,
+        pid,
+        user_id
+    )
+    .fetch_one(tx)
+    .await?;
+
+    if is_blocked {
+        return Err(Error::Forbidden);
+    }
+
+    Ok(())
+}
+
+impl Validate for NewComment {
+    fn check(&self, violations: &mut Violations) {
+        check_content(violations, &self.content);
+    }
+}
+
+impl Validate for CommentEdit {
+    fn check(&self, violations: &mut Violations) {
+        check_content(violations, &self.content);
+    }
+}
+
+fn check_content(violations: &mut Violations, content: &str) {
+    violations
+        .field("content", content)
+        .not_blank()
+        .max_chars(MAX_CONTENT_LENGTH);
+}
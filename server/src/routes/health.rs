@@ -0,0 +1,103 @@
+use super::AppState;
+use crate::db;
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use std::time::Duration;
+
+/// How long readiness checks wait on the database before giving up.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Readiness {
+    database: Check,
+    migrations: Check,
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed(error: impl ToString) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    build_time: &'static str,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+}
+
+/// Succeeds as long as the process is serving requests.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Succeeds when the database can be queried and every migration has been
+/// applied, and fails with `503 Service Unavailable` otherwise.
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let database = match tokio::time::timeout(
+        READINESS_TIMEOUT,
+        sqlx::query!("select 1 as one").fetch_one(&state.db),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Check::ok(),
+        Ok(Err(e)) => Check::failed(e),
+        Err(_) => Check::failed("timed out"),
+    };
+
+    let migrations = if database.ok {
+        match db::pending_migrations(&state.db).await {
+            Ok(pending) if pending.is_empty() => Check::ok(),
+            Ok(pending) => Check::failed(format!("{} pending", pending.len())),
+            Err(e) => Check::failed(e),
+        }
+    } else {
+        Check::failed("database unavailable")
+    };
+
+    let status = if database.ok && migrations.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(Readiness {
+            database,
+            migrations,
+        }),
+    )
+}
+
+async fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        build_time: env!("BUILD_TIME"),
+    })
+}
@@ -0,0 +1,372 @@
+use super::stream::{Event, Topic};
+use super::{AppState, Error, Pagination, Result};
+use crate::auth::AuthUser;
+use crate::rate_limit::{self, Action, ClientIp};
+use crate::validate::{ValidJson, Validate, Violations};
+use axum::{
+    extract::{Path, Query, State},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+const MAX_MESSAGE_LENGTH: usize = 10_000;
+
+#[derive(Deserialize)]
+struct NewMessage {
+    username: String,
+    content: String,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct Message {
+    id: i64,
+    conversation_id: i64,
+    sender: String,
+    content: String,
+    created_at: DateTime<Local>,
+    is_read: bool,
+}
+
+#[derive(Serialize)]
+struct Conversation {
+    id: i64,
+    /// The other user in the conversation.
+    username: String,
+    avatar_thumbnail_url: Option<String>,
+    last_message: Option<String>,
+    last_sender: Option<String>,
+    last_message_at: DateTime<Local>,
+    unread_count: i64,
+}
+
+#[derive(Serialize)]
+struct Conversations {
+    conversations: Vec<Conversation>,
+    unread_count: i64,
+}
+
+#[derive(Serialize)]
+struct History {
+    id: i64,
+    username: String,
+    /// Newest first.
+    messages: Vec<Message>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MessageSettings {
+    /// Only accept messages from users the caller follows.
+    from_following_only: bool,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/messages", get(get_conversations).post(send_message))
+        .route(
+            "/api/messages/settings",
+            get(get_settings).put(update_settings),
+        )
+        .route("/api/messages/:id", get(get_history))
+}
+
+/// Sends a message to a user, starting a conversation with them if there
+/// isn't one yet.
+async fn send_message(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    ValidJson(req): ValidJson<NewMessage>,
+) -> Result<Json<Message>> {
+    let content = req.content.trim();
+
+    let permit = rate_limit::check(&state, auth_user.id, ip, Action::Message).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    let recipient = sqlx::query!(
+        r#"
+            select
+                id,
+                dms_from_following_only,
+                exists(
+                    select *
+                    from blocks
+                    where (blocker_user_id = a.id and blocked_user_id = $2)
+                        or (blocker_user_id = $2 and blocked_user_id = a.id)
+                ) as "is_blocked!",
+                exists(
+                    select *
+                    from follows
+                    where followee_user_id = $2
+                        and follower_user_id = a.id
+                ) as "follows_sender!"
+            from users a
+            where username = $1
+        "#,
+        req.username,
+        auth_user.id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if recipient.id == auth_user.id {
+        return Err(Error::unprocessable_entity([(
+            "username",
+            "can't send a message to yourself",
+        )]));
+    }
+
+    if recipient.is_blocked || (recipient.dms_from_following_only && !recipient.follows_sender) {
+        return Err(Error::Forbidden);
+    }
+
+    let conversation_id = sqlx::query_scalar!(
+        "
+            insert into conversations(user_a_id, user_b_id)
+            values(least($1::bigint, $2::bigint), greatest($1::bigint, $2::bigint))
+            on conflict (user_a_id, user_b_id) do update
+            set last_message_at = now()
+            returning id
+        ",
+        auth_user.id,
+        recipient.id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let message = sqlx::query_as!(
+        Message,
+        r#"
+            with message as (
+                insert into messages(conversation_id, sender_user_id, content)
+                values($1, $2, $3)
+                returning *
+            )
+            select
+                a.id,
+                a.conversation_id,
+                b.username as sender,
+                a.content,
+                a.created_at as "created_at: DateTime<Local>",
+                false as "is_read!"
+            from message a
+            join users b on a.sender_user_id = b.id
+        "#,
+        conversation_id,
+        auth_user.id,
+        content
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    permit.record(&state.rate_limiter)?;
+    tx.commit().await?;
+
+    state
+        .events
+        .publish(Topic::User(recipient.id), Event::Message(message.clone()));
+
+    Ok(Json(message))
+}
+
+/// The caller's conversations, most recently active first.
+async fn get_conversations(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Conversations>> {
+    let conversations = sqlx::query_as!(
+        Conversation,
+        r#"
+            select
+                a.id,
+                b.username,
+                b.avatar_thumbnail_url,
+                case
+                    when c.removed_at is null then c.content
+                    else '[removed]'
+                end as "last_message?",
+                d.username as "last_sender?",
+                a.last_message_at as "last_message_at: DateTime<Local>",
+                (
+                    select count(*)
+                    from messages
+                    where conversation_id = a.id
+                        and sender_user_id <> $1
+                        and read_at is null
+                ) as "unread_count!"
+            from conversations a
+            join users b on b.id = case
+                when a.user_a_id = $1 then a.user_b_id
+                else a.user_a_id
+            end
+            left join lateral (
+                select content, sender_user_id, removed_at
+                from messages
+                where conversation_id = a.id
+                order by created_at desc
+                limit 1
+            ) c on true
+            left join users d on d.id = c.sender_user_id
+            where $1 in (a.user_a_id, a.user_b_id)
+            order by a.last_message_at desc
+            limit $2
+            offset $3
+        "#,
+        auth_user.id,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let unread_count = sqlx::query_scalar!(
+        r#"
+            select count(*) as "count!"
+            from messages a
+            join conversations b on a.conversation_id = b.id
+            where $1 in (b.user_a_id, b.user_b_id)
+                and a.sender_user_id <> $1
+                and a.read_at is null
+        "#,
+        auth_user.id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(Conversations {
+        conversations,
+        unread_count,
+    }))
+}
+
+/// A page of a conversation's messages. Marks the messages the caller has
+/// received in it as read.
+async fn get_history(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<History>> {
+    let mut tx = state.db.begin().await?;
+
+    let username = sqlx::query_scalar!(
+        "
+            select b.username
+            from conversations a
+            join users b on b.id = case
+                when a.user_a_id = $2 then a.user_b_id
+                else a.user_a_id
+            end
+            where a.id = $1
+                and $2 in (a.user_a_id, a.user_b_id)
+        ",
+        id,
+        auth_user.id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    sqlx::query!(
+        "
+            update messages
+            set read_at = now()
+            where conversation_id = $1
+                and sender_user_id <> $2
+                and read_at is null
+        ",
+        id,
+        auth_user.id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let messages = sqlx::query_as!(
+        Message,
+        r#"
+            select
+                a.id,
+                a.conversation_id,
+                b.username as sender,
+                case
+                    when a.removed_at is null then a.content
+                    else '[removed]'
+                end as "content!",
+                a.created_at as "created_at: DateTime<Local>",
+                a.read_at is not null as "is_read!"
+            from messages a
+            join users b on a.sender_user_id = b.id
+            where a.conversation_id = $1
+            order by a.created_at desc, a.id desc
+            limit $2
+            offset $3
+        "#,
+        id,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(History {
+        id,
+        username,
+        messages,
+    }))
+}
+
+async fn get_settings(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<MessageSettings>> {
+    let settings = sqlx::query_as!(
+        MessageSettings,
+        "
+            select dms_from_following_only as from_following_only
+            from users
+            where id = $1
+        ",
+        auth_user.id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(settings))
+}
+
+async fn update_settings(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Json(req): Json<MessageSettings>,
+) -> Result<Json<MessageSettings>> {
+    let settings = sqlx::query_as!(
+        MessageSettings,
+        "
+            update users
+            set dms_from_following_only = $2
+            where id = $1
+            returning dms_from_following_only as from_following_only
+        ",
+        auth_user.id,
+        req.from_following_only
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(settings))
+}
+
+impl Validate for NewMessage {
+    fn check(&self, violations: &mut Violations) {
+        violations
+            .field("content", self.content.trim())
+            .not_blank()
+            .max_chars(MAX_MESSAGE_LENGTH);
+    }
+}
@@ -1,13 +1,34 @@
+use crate::automod::AutoMod;
+use crate::rate_limit::{self, RateLimiter};
+use crate::shutdown::Shutdown;
+use crate::storage::Storage;
+use crate::telemetry;
 use axum::{
+    extract::DefaultBodyLimit,
     http::{HeaderValue, Method},
+    middleware,
     routing::get,
     Json, Router,
 };
+use serde::Deserialize;
 use sqlx::PgPool;
-use tower_http::cors::CorsLayer;
+use std::sync::Arc;
+use tower_http::{
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 
+mod automod;
+pub(crate) mod bans;
 mod comments;
+mod health;
+mod messages;
+mod moderation;
+mod notifications;
 mod profiles;
+mod search;
+mod stream;
 mod threads;
 pub mod users;
 
@@ -15,19 +36,58 @@ pub mod users;
 pub(crate) struct AppState {
     pub db: PgPool,
     pub key: String,
+    pub events: stream::EventBus,
+    pub storage: Arc<dyn Storage>,
+    pub automod: Arc<AutoMod>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub shutdown: Shutdown,
 }
 
 pub use crate::error::{Error, ResultExt};
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub fn router(db: PgPool) -> Router {
+const DEFAULT_PAGE_SIZE: i64 = 25;
+const MAX_PAGE_SIZE: i64 = 100;
+/// Routes that take uploads set their own limit.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+#[derive(Deserialize)]
+pub(crate) struct Pagination {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl Pagination {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+pub fn router(
+    db: PgPool,
+    storage: Arc<dyn Storage>,
+    automod: Arc<AutoMod>,
+    rate_limiter: RateLimiter,
+    shutdown: Shutdown,
+) -> Router {
     let app_state = AppState {
         db,
         key: "secret_idk".to_string(),
+        events: stream::EventBus::new(),
+        storage,
+        automod,
+        rate_limiter: Arc::new(rate_limiter),
+        shutdown,
     };
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_origin("https://54.185.58.189".parse::<HeaderValue>().unwrap());
 
     Router::new()
@@ -36,7 +96,30 @@ pub fn router(db: PgPool) -> Router {
         .merge(profiles::router())
         .merge(threads::router())
         .merge(comments::router())
+        .merge(notifications::router())
+        .merge(messages::router())
+        .merge(moderation::router())
+        .merge(bans::router())
+        .merge(automod::router())
+        .merge(search::router())
+        .merge(stream::router())
+        .layer(DefaultBodyLimit::max(MAX_BODY_BYTES))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit::limit_requests,
+        ))
+        // Probes aren't rate limited.
+        .merge(health::router())
         .layer(cors)
+        .layer(middleware::from_fn(telemetry::record_metrics))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(telemetry::make_span)
+                .on_response(telemetry::on_response),
+        )
+        .layer(middleware::from_fn(telemetry::scope_request_id))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .with_state(app_state)
 }
 
@@ -0,0 +1,847 @@
+use super::{AppState, Error, Pagination, Result};
+use crate::auth::{AuthUser, MaybeAuthUser, ModUser, Role};
+use crate::mod_log::{self, ModAction, ModLogEntry, Snapshot};
+use crate::validate::{ValidJson, Validate, Violations};
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+const MAX_NOTE_LENGTH: usize = 1000;
+const MAX_REASON_LENGTH: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReportTarget {
+    Thread,
+    Comment,
+    User,
+    Message,
+}
+
+impl ReportTarget {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Thread => "thread",
+            Self::Comment => "comment",
+            Self::User => "user",
+            Self::Message => "message",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ReportReason {
+    Spam,
+    Harassment,
+    HateSpeech,
+    Misinformation,
+    OffTopic,
+    Other,
+}
+
+impl ReportReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Spam => "spam",
+            Self::Harassment => "harassment",
+            Self::HateSpeech => "hate_speech",
+            Self::Misinformation => "misinformation",
+            Self::OffTopic => "off_topic",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewReport {
+    reason: ReportReason,
+    note: Option<String>,
+}
+
+#[derive(Serialize)]
+struct QueueItem {
+    target_kind: String,
+    target_id: i64,
+    author: Option<String>,
+    thread_slug: Option<String>,
+    thread_title: Option<String>,
+    content: Option<String>,
+    is_removed: bool,
+    /// Held for review by the automoderator until the reports are dismissed.
+    is_held: bool,
+    report_count: i64,
+    reasons: Vec<String>,
+    notes: Vec<String>,
+    first_reported_at: DateTime<Local>,
+    last_reported_at: DateTime<Local>,
+}
+
+#[derive(Serialize)]
+struct ReportsClosed {
+    count: u64,
+}
+
+/// Optional body for moderator actions, explaining the action in the log.
+#[derive(Deserialize)]
+struct ModReason {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RoleChange {
+    role: Role,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LogFilter {
+    moderator: Option<String>,
+    target_user: Option<String>,
+    action: Option<ModAction>,
+}
+
+#[derive(Serialize)]
+struct LogEntry {
+    id: i64,
+    moderator: String,
+    action: String,
+    target_kind: String,
+    target_id: i64,
+    target_user: Option<String>,
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after: Option<serde_json::Value>,
+    created_at: DateTime<Local>,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/threads/:slug/report", post(report_thread))
+        .route(
+            "/api/threads/:slug/comments/:id/report",
+            post(report_comment),
+        )
+        .route("/api/profiles/:username/report", post(report_user))
+        .route("/api/messages/:id/:message_id/report", post(report_message))
+        .route("/api/mod/queue", get(get_queue))
+        .route("/api/mod/queue/:kind/:id/resolve", post(resolve_reports))
+        .route("/api/mod/queue/:kind/:id/dismiss", post(dismiss_reports))
+        .route("/api/mod/queue/:kind/:id/remove", post(remove_content))
+        .route("/api/mod/threads/:slug/lock", post(lock_thread))
+        .route("/api/mod/threads/:slug/unlock", post(unlock_thread))
+        .route("/api/mod/threads/:slug/pin", post(pin_thread))
+        .route("/api/mod/threads/:slug/unpin", post(unpin_thread))
+        .route("/api/mod/users/:username/role", put(change_role))
+        .route("/api/mod/log", get(get_log))
+}
+
+async fn report_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    ValidJson(req): ValidJson<NewReport>,
+) -> Result<()> {
+    let thread_id = sqlx::query_scalar!(
+        "
+            select id
+            from threads
+            where slug = $1
+        ",
+        slug
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    create_report(&state, auth_user.id, ReportTarget::Thread, thread_id, req).await
+}
+
+async fn report_comment(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path((slug, id)): Path<(String, String)>,
+    ValidJson(req): ValidJson<NewReport>,
+) -> Result<()> {
+    let id = i64::from_str_radix(&id, 36).map_err(|_| Error::NotFound)?;
+
+    let comment_id = sqlx::query_scalar!(
+        "
+            select a.id
+            from comments a
+            join threads b on a.thread_id = b.id
+            where b.slug = $1
+                and a.id = $2
+        ",
+        slug,
+        id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    create_report(&state, auth_user.id, ReportTarget::Comment, comment_id, req).await
+}
+
+async fn report_user(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    ValidJson(req): ValidJson<NewReport>,
+) -> Result<()> {
+    let user_id = sqlx::query_scalar!(
+        "
+            select id
+            from users
+            where username = $1
+        ",
+        username
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    create_report(&state, auth_user.id, ReportTarget::User, user_id, req).await
+}
+
+/// Reports a message the caller received.
+async fn report_message(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(i64, i64)>,
+    ValidJson(req): ValidJson<NewReport>,
+) -> Result<()> {
+    let message_id = sqlx::query_scalar!(
+        "
+            select a.id
+            from messages a
+            join conversations b on a.conversation_id = b.id
+            where a.id = $2
+                and b.id = $1
+                and $3 in (b.user_a_id, b.user_b_id)
+                and a.sender_user_id <> $3
+        ",
+        id,
+        message_id,
+        auth_user.id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    create_report(&state, auth_user.id, ReportTarget::Message, message_id, req).await
+}
+
+/// Files a report, ignoring repeat reports of the same item by the same user
+/// while their earlier report is still open.
+async fn create_report(
+    state: &AppState,
+    reporter_user_id: i64,
+    target: ReportTarget,
+    target_id: i64,
+    req: NewReport,
+) -> Result<()> {
+    let note = req
+        .note
+        .map(|note| note.trim().to_string())
+        .filter(|note| !note.is_empty());
+
+    sqlx::query!(
+        "
+            insert into reports(reporter_user_id, target_kind, target_id, reason, note)
+            values($1, $2, $3, $4, $5)
+            on conflict (reporter_user_id, target_kind, target_id) where status = 'open'
+            do nothing
+        ",
+        reporter_user_id,
+        target.as_str(),
+        target_id,
+        req.reason.as_str(),
+        note
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+/// Open reports grouped by the item they're about, most reported first.
+async fn get_queue(
+    _mod_user: ModUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<QueueItem>>> {
+    let queue = sqlx::query_as!(
+        QueueItem,
+        r#"
+            select
+                a.target_kind as "target_kind!",
+                a.target_id as "target_id!",
+                e.username as "author?",
+                coalesce(b.slug, d.slug) as "thread_slug?",
+                coalesce(b.title, d.title) as "thread_title?",
+                coalesce(b.content, c.content, f.content) as "content?",
+                coalesce(b.removed_at, c.removed_at, f.removed_at) is not null as "is_removed!",
+                coalesce(b.held_at, c.held_at) is not null as "is_held!",
+                a.report_count as "report_count!",
+                a.reasons as "reasons!",
+                a.notes as "notes!",
+                a.first_reported_at as "first_reported_at!: DateTime<Local>",
+                a.last_reported_at as "last_reported_at!: DateTime<Local>"
+            from (
+                select
+                    target_kind,
+                    target_id,
+                    count(*) as report_count,
+                    array_agg(distinct reason) as reasons,
+                    array_remove(array_agg(note order by created_at), null) as notes,
+                    min(created_at) as first_reported_at,
+                    max(created_at) as last_reported_at
+                from reports
+                where status = 'open'
+                group by target_kind, target_id
+            ) a
+            left join threads b on a.target_kind = 'thread' and b.id = a.target_id
+            left join comments c on a.target_kind = 'comment' and c.id = a.target_id
+            left join threads d on d.id = c.thread_id
+            left join messages f on a.target_kind = 'message' and f.id = a.target_id
+            left join users e on e.id = case a.target_kind
+                when 'thread' then b.user_id
+                when 'comment' then c.user_id
+                when 'message' then f.sender_user_id
+                else a.target_id
+            end
+            order by a.report_count desc, a.first_reported_at
+            limit $1
+            offset $2
+        "#,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(queue))
+}
+
+/// Closes the open reports on an item after a moderator has dealt with it.
+async fn resolve_reports(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path((kind, id)): Path<(ReportTarget, i64)>,
+    body: Option<Json<ModReason>>,
+) -> Result<Json<ReportsClosed>> {
+    let mut tx = state.db.begin().await?;
+
+    let count = close_reports(&mut tx, mod_user.id, kind, id, "resolved").await?;
+    let target_user_id = target_owner(&mut tx, kind, id).await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action: ModAction::ResolveReports,
+            target_kind: kind.as_str(),
+            target_id: id,
+            target_user_id,
+            reason: reason(&body),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ReportsClosed { count }))
+}
+
+/// Closes the open reports on an item without taking any action. Content held
+/// by the automoderator is approved.
+async fn dismiss_reports(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path((kind, id)): Path<(ReportTarget, i64)>,
+    body: Option<Json<ModReason>>,
+) -> Result<Json<ReportsClosed>> {
+    let mut tx = state.db.begin().await?;
+
+    let count = close_reports(&mut tx, mod_user.id, kind, id, "dismissed").await?;
+    let target_user_id = target_owner(&mut tx, kind, id).await?;
+
+    match kind {
+        ReportTarget::Thread => {
+            sqlx::query!(
+                "
+                    update threads
+                    set held_at = null
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        ReportTarget::Comment => {
+            sqlx::query!(
+                "
+                    update comments
+                    set held_at = null
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        ReportTarget::User | ReportTarget::Message => (),
+    }
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action: ModAction::DismissReports,
+            target_kind: kind.as_str(),
+            target_id: id,
+            target_user_id,
+            reason: reason(&body),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ReportsClosed { count }))
+}
+
+/// Removes a reported thread or comment and resolves its reports.
+async fn remove_content(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path((kind, id)): Path<(ReportTarget, i64)>,
+    body: Option<Json<ModReason>>,
+) -> Result<Json<ReportsClosed>> {
+    let mut tx = state.db.begin().await?;
+
+    let (action, before, after) = match kind {
+        ReportTarget::Thread => {
+            let before = mod_log::thread_snapshot(&mut tx, id)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            sqlx::query!(
+                "
+                    update threads
+                    set removed_at = coalesce(removed_at, now())
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+
+            let after = mod_log::thread_snapshot(&mut tx, id)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            (ModAction::RemoveThread, before, after)
+        }
+        ReportTarget::Comment => {
+            let before = mod_log::comment_snapshot(&mut tx, id)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            sqlx::query!(
+                "
+                    update comments
+                    set removed_at = coalesce(removed_at, now())
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+
+            let after = mod_log::comment_snapshot(&mut tx, id)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            (ModAction::RemoveComment, before, after)
+        }
+        ReportTarget::Message => {
+            let before = mod_log::message_snapshot(&mut tx, id)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            sqlx::query!(
+                "
+                    update messages
+                    set removed_at = coalesce(removed_at, now())
+                    where id = $1
+                ",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+
+            let after = mod_log::message_snapshot(&mut tx, id)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            (ModAction::RemoveMessage, before, after)
+        }
+        ReportTarget::User => {
+            return Err(Error::unprocessable_entity([(
+                "target_kind",
+                "users can't be removed",
+            )]))
+        }
+    };
+
+    let count = close_reports(&mut tx, mod_user.id, kind, id, "resolved").await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action,
+            target_kind: kind.as_str(),
+            target_id: id,
+            target_user_id: Some(before.user_id),
+            reason: reason(&body),
+            before: Some(before.value),
+            after: Some(after.value),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ReportsClosed { count }))
+}
+
+async fn lock_thread(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    body: Option<Json<ModReason>>,
+) -> Result<()> {
+    update_thread_flags(&state, mod_user, slug, ModAction::LockThread, reason(&body)).await
+}
+
+async fn unlock_thread(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    body: Option<Json<ModReason>>,
+) -> Result<()> {
+    update_thread_flags(
+        &state,
+        mod_user,
+        slug,
+        ModAction::UnlockThread,
+        reason(&body),
+    )
+    .await
+}
+
+async fn pin_thread(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    body: Option<Json<ModReason>>,
+) -> Result<()> {
+    update_thread_flags(&state, mod_user, slug, ModAction::PinThread, reason(&body)).await
+}
+
+async fn unpin_thread(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    body: Option<Json<ModReason>>,
+) -> Result<()> {
+    update_thread_flags(
+        &state,
+        mod_user,
+        slug,
+        ModAction::UnpinThread,
+        reason(&body),
+    )
+    .await
+}
+
+/// Locks, unlocks, pins or unpins a thread, logging the change.
+async fn update_thread_flags(
+    state: &AppState,
+    mod_user: ModUser,
+    slug: String,
+    action: ModAction,
+    reason: Option<&str>,
+) -> Result<()> {
+    let mut tx = state.db.begin().await?;
+
+    let id = sqlx::query_scalar!(
+        "
+            select id
+            from threads
+            where slug = $1
+            for update
+        ",
+        slug
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let before = mod_log::thread_snapshot(&mut tx, id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    let (locked, pinned) = match action {
+        ModAction::LockThread => (Some(true), None),
+        ModAction::UnlockThread => (Some(false), None),
+        ModAction::PinThread => (None, Some(true)),
+        ModAction::UnpinThread => (None, Some(false)),
+        _ => unreachable!("not a thread flag action: {:?}", action),
+    };
+
+    sqlx::query!(
+        "
+            update threads
+            set locked_at = case
+                    when $2::boolean is null then locked_at
+                    when $2 then coalesce(locked_at, now())
+                end,
+                pinned_at = case
+                    when $3::boolean is null then pinned_at
+                    when $3 then coalesce(pinned_at, now())
+                end
+            where id = $1
+        ",
+        id,
+        locked,
+        pinned
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let after = mod_log::thread_snapshot(&mut tx, id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action,
+            target_kind: ReportTarget::Thread.as_str(),
+            target_id: id,
+            target_user_id: Some(before.user_id),
+            reason,
+            before: Some(before.value),
+            after: Some(after.value),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Changes a user's role. Only admins can do this, and not to themselves.
+async fn change_role(
+    mod_user: ModUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    ValidJson(req): ValidJson<RoleChange>,
+) -> Result<()> {
+    if mod_user.role != Role::Admin {
+        return Err(Error::Forbidden);
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    let user = sqlx::query!(
+        "
+            select id, role
+            from users
+            where username = $1
+            for update
+        ",
+        username
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if user.id == mod_user.id {
+        return Err(Error::Forbidden);
+    }
+
+    sqlx::query!(
+        "
+            update users
+            set role = $2
+            where id = $1
+        ",
+        user.id,
+        req.role.as_str()
+    )
+    .execute(&mut tx)
+    .await?;
+
+    mod_log::record(
+        &mut tx,
+        ModLogEntry {
+            actor_user_id: mod_user.id,
+            action: ModAction::ChangeRole,
+            target_kind: ReportTarget::User.as_str(),
+            target_id: user.id,
+            target_user_id: Some(user.id),
+            reason: req.reason.as_deref(),
+            before: Some(serde_json::json!({ "role": user.role })),
+            after: Some(serde_json::json!({ "role": req.role })),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// The moderation log, newest first. Anyone can read it, but the before and
+/// after snapshots of removed content are only included for moderators.
+async fn get_log(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Query(filter): Query<LogFilter>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<LogEntry>>> {
+    let is_moderator = match &auth_user.0 {
+        Some(auth_user) => auth_user.role(&state).await? >= Role::Moderator,
+        None => false,
+    };
+
+    let mut entries = sqlx::query_as!(
+        LogEntry,
+        r#"
+            select
+                a.id,
+                b.username as moderator,
+                a.action,
+                a.target_kind,
+                a.target_id,
+                c.username as "target_user?",
+                a.reason,
+                a.before,
+                a.after,
+                a.created_at as "created_at: DateTime<Local>"
+            from mod_log a
+            join users b on a.actor_user_id = b.id
+            left join users c on a.target_user_id = c.id
+            where ($1::text is null or b.username = $1)
+                and ($2::text is null or c.username = $2)
+                and ($3::text is null or a.action = $3)
+            order by a.created_at desc
+            limit $4
+            offset $5
+        "#,
+        filter.moderator,
+        filter.target_user,
+        filter.action.map(|action| action.as_str()),
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    if !is_moderator {
+        for entry in &mut entries {
+            entry.before = None;
+            entry.after = None;
+        }
+    }
+
+    Ok(Json(entries))
+}
+
+fn reason(body: &Option<Json<ModReason>>) -> Option<&str> {
+    body.as_ref()
+        .and_then(|Json(body)| body.reason.as_deref())
+        .map(str::trim)
+        .filter(|reason| !reason.is_empty())
+}
+
+async fn target_owner(
+    tx: &mut Transaction<'_, Postgres>,
+    kind: ReportTarget,
+    id: i64,
+) -> Result<Option<i64>> {
+    let snapshot = match kind {
+        ReportTarget::Thread => mod_log::thread_snapshot(tx, id).await?,
+        ReportTarget::Comment => mod_log::comment_snapshot(tx, id).await?,
+        ReportTarget::Message => mod_log::message_snapshot(tx, id).await?,
+        ReportTarget::User => return Ok(Some(id)),
+    };
+
+    Ok(snapshot.map(|Snapshot { user_id, .. }| user_id))
+}
+
+async fn close_reports(
+    tx: &mut Transaction<'_, Postgres>,
+    mod_user_id: i64,
+    kind: ReportTarget,
+    id: i64,
+    status: &str,
+) -> Result<u64> {
+    Ok(sqlx::query!(
+        "
+            update reports
+            set status = $4,
+                resolved_by_user_id = $1,
+                resolved_at = now()
+            where target_kind = $2
+                and target_id = $3
+                and status = 'open'
+        ",
+        mod_user_id,
+        kind.as_str(),
+        id,
+        status
+    )
+    .execute(tx)
+    .await?
+    .rows_affected())
+}
+
+impl Validate for NewReport {
+    fn check(&self, violations: &mut Violations) {
+        if let Some(note) = &self.note {
+            violations
+                .field("note", note.trim())
+                .max_chars(MAX_NOTE_LENGTH);
+        }
+    }
+}
+
+impl Validate for RoleChange {
+    fn check(&self, violations: &mut Violations) {
+        if let Some(reason) = &self.reason {
+            violations
+                .field("reason", reason)
+                .max_chars(MAX_REASON_LENGTH);
+        }
+    }
+}
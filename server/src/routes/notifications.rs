@@ -0,0 +1,465 @@
+use super::{AppState, Error, Pagination, Result};
+use crate::auth::AuthUser;
+use crate::notifications::NotificationKind;
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct InboxFilter {
+    #[serde(default)]
+    unread: bool,
+}
+
+#[derive(Serialize)]
+struct Notification {
+    id: i64,
+    kind: String,
+    actor: String,
+    thread_slug: Option<String>,
+    thread_title: Option<String>,
+    comment_id: Option<i64>,
+    is_read: bool,
+    created_at: DateTime<Local>,
+}
+
+#[derive(Serialize)]
+struct Inbox {
+    notifications: Vec<Notification>,
+    unread_count: i64,
+}
+
+#[derive(Serialize)]
+struct UnreadCount {
+    count: i64,
+}
+
+#[derive(Serialize)]
+struct Mutes {
+    muted: Vec<String>,
+    /// Slugs of the threads the user has muted.
+    muted_threads: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct WatchedThread {
+    slug: String,
+    title: String,
+    username: String,
+    comment_count: i64,
+    /// Comments by others since the user last read the thread.
+    unread_count: i64,
+    last_activity_at: DateTime<Local>,
+    watched_at: DateTime<Local>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NotificationSettings {
+    /// Watch threads the user creates or comments on.
+    auto_watch: bool,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/notifications", get(get_notifications))
+        .route("/api/notifications/read", post(mark_all_read))
+        .route("/api/notifications/:id/read", post(mark_read))
+        .route("/api/notifications/mutes", get(get_mutes))
+        .route(
+            "/api/notifications/mutes/:kind",
+            post(mute_kind).delete(unmute_kind),
+        )
+        .route(
+            "/api/notifications/settings",
+            get(get_settings).put(update_settings),
+        )
+        .route(
+            "/api/threads/:slug/mute",
+            post(mute_thread).delete(unmute_thread),
+        )
+        .route(
+            "/api/threads/:slug/watch",
+            post(watch_thread).delete(unwatch_thread),
+        )
+        .route("/api/users/watching", get(get_watching))
+}
+
+async fn get_notifications(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+    Query(filter): Query<InboxFilter>,
+) -> Result<Json<Inbox>> {
+    let notifications = sqlx::query_as!(
+        Notification,
+        r#"
+            select
+                a.id,
+                kind,
+                b.username as actor,
+                c.slug as "thread_slug?",
+                c.title as "thread_title?",
+                comment_id,
+                is_read,
+                a.created_at as "created_at: DateTime<Local>"
+            from notifications a
+            join users b on a.actor_user_id = b.id
+            left join threads c on a.thread_id = c.id
+            where a.user_id = $1
+                and (not $2 or not is_read)
+            order by a.created_at desc
+            limit $3
+            offset $4
+        "#,
+        auth_user.id,
+        filter.unread,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let unread_count = unread_count(&state, auth_user.id).await?;
+
+    Ok(Json(Inbox {
+        notifications,
+        unread_count,
+    }))
+}
+
+async fn mark_read(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<UnreadCount>> {
+    sqlx::query!(
+        "
+            update notifications
+            set is_read = true
+            where id = $1
+                and user_id = $2
+            returning id
+        ",
+        id,
+        auth_user.id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let count = unread_count(&state, auth_user.id).await?;
+
+    Ok(Json(UnreadCount { count }))
+}
+
+async fn mark_all_read(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<UnreadCount>> {
+    sqlx::query!(
+        "
+            update notifications
+            set is_read = true
+            where user_id = $1
+                and not is_read
+        ",
+        auth_user.id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(Json(UnreadCount { count: 0 }))
+}
+
+async fn get_mutes(auth_user: AuthUser, State(state): State<AppState>) -> Result<Json<Mutes>> {
+    let muted = sqlx::query_scalar!(
+        "
+            select kind
+            from notification_mutes
+            where user_id = $1
+            order by kind
+        ",
+        auth_user.id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let muted_threads = sqlx::query_scalar!(
+        "
+            select b.slug
+            from thread_mutes a
+            join threads b on a.thread_id = b.id
+            where a.user_id = $1
+            order by a.created_at desc
+        ",
+        auth_user.id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(Mutes {
+        muted,
+        muted_threads,
+    }))
+}
+
+async fn mute_kind(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(kind): Path<NotificationKind>,
+) -> Result<Json<Mutes>> {
+    sqlx::query!(
+        "
+            insert into notification_mutes(user_id, kind)
+            values($1, $2)
+            on conflict do nothing
+        ",
+        auth_user.id,
+        kind.as_str()
+    )
+    .execute(&state.db)
+    .await?;
+
+    get_mutes(auth_user, State(state)).await
+}
+
+async fn unmute_kind(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(kind): Path<NotificationKind>,
+) -> Result<Json<Mutes>> {
+    sqlx::query!(
+        "
+            delete from notification_mutes
+            where user_id = $1
+                and kind = $2
+        ",
+        auth_user.id,
+        kind.as_str()
+    )
+    .execute(&state.db)
+    .await?;
+
+    get_mutes(auth_user, State(state)).await
+}
+
+/// Stops a thread from generating notifications for the user.
+async fn mute_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<Json<Mutes>> {
+    let thread_id = sqlx::query_scalar!(
+        "
+            select id
+            from threads
+            where slug = $1
+        ",
+        slug
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    sqlx::query!(
+        "
+            insert into thread_mutes(user_id, thread_id)
+            values($1, $2)
+            on conflict do nothing
+        ",
+        auth_user.id,
+        thread_id
+    )
+    .execute(&state.db)
+    .await?;
+
+    get_mutes(auth_user, State(state)).await
+}
+
+async fn unmute_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<Json<Mutes>> {
+    sqlx::query!(
+        "
+            delete from thread_mutes
+            where user_id = $1
+                and thread_id = (select id from threads where slug = $2)
+        ",
+        auth_user.id,
+        slug
+    )
+    .execute(&state.db)
+    .await?;
+
+    get_mutes(auth_user, State(state)).await
+}
+
+/// Notifies the user of every new comment on a thread.
+async fn watch_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<()> {
+    let thread_id = sqlx::query_scalar!(
+        "
+            select id
+            from threads
+            where slug = $1
+                and removed_at is null
+        ",
+        slug
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    sqlx::query!(
+        "
+            insert into thread_watches(user_id, thread_id)
+            values($1, $2)
+            on conflict do nothing
+        ",
+        auth_user.id,
+        thread_id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+async fn unwatch_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<()> {
+    sqlx::query!(
+        "
+            delete from thread_watches
+            where user_id = $1
+                and thread_id = (select id from threads where slug = $2)
+        ",
+        auth_user.id,
+        slug
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+/// The threads the caller is watching, most recently active first.
+async fn get_watching(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<WatchedThread>>> {
+    let threads = sqlx::query_as!(
+        WatchedThread,
+        r#"
+            select
+                b.slug,
+                b.title,
+                c.username,
+                d.comment_count as "comment_count!",
+                d.unread_count as "unread_count!",
+                greatest(b.created_at, d.last_comment_at) as "last_activity_at!: DateTime<Local>",
+                a.created_at as "watched_at: DateTime<Local>"
+            from thread_watches a
+            join threads b on a.thread_id = b.id
+            join users c on b.user_id = c.id
+            cross join lateral (
+                select
+                    count(*) as comment_count,
+                    count(*) filter (
+                        where created_at > a.last_read_at
+                            and user_id <> $1
+                    ) as unread_count,
+                    max(created_at) as last_comment_at
+                from comments
+                where thread_id = b.id
+                    and removed_at is null
+                    and ((not shadowed and held_at is null) or user_id = $1)
+                    and not exists(
+                        select *
+                        from blocks
+                        where blocker_user_id = $1
+                            and blocked_user_id = comments.user_id
+                    )
+            ) d
+            where a.user_id = $1
+                and b.removed_at is null
+            order by greatest(b.created_at, d.last_comment_at) desc
+            limit $2
+            offset $3
+        "#,
+        auth_user.id,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(threads))
+}
+
+async fn get_settings(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<NotificationSettings>> {
+    let settings = sqlx::query_as!(
+        NotificationSettings,
+        "
+            select auto_watch
+            from users
+            where id = $1
+        ",
+        auth_user.id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(settings))
+}
+
+async fn update_settings(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Json(req): Json<NotificationSettings>,
+) -> Result<Json<NotificationSettings>> {
+    let settings = sqlx::query_as!(
+        NotificationSettings,
+        "
+            update users
+            set auto_watch = $2
+            where id = $1
+            returning auto_watch
+        ",
+        auth_user.id,
+        req.auto_watch
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(settings))
+}
+
+async fn unread_count(state: &AppState, user_id: i64) -> Result<i64> {
+    Ok(sqlx::query_scalar!(
+        r#"
+            select count(*) as "count!"
+            from notifications
+            where user_id = $1
+                and not is_read
+        "#,
+        user_id
+    )
+    .fetch_one(&state.db)
+    .await?)
+}
@@ -1,32 +1,136 @@
 use super::threads::Thread;
 use super::AppState;
-use super::Result;
+use super::{Pagination, Result};
 use crate::auth::AuthUser;
 use crate::auth::MaybeAuthUser;
 use crate::error::{Error, ResultExt};
+use crate::notifications::{notify, NewNotification, NotificationKind};
+use crate::validate::{ValidJson, Validate, Violations};
+use anyhow::Context;
 use axum::{
-    extract::{Path, State},
-    routing::{get, post},
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    routing::{get, post, put},
     Json, Router,
 };
 use chrono::{DateTime, Local};
-use serde::Serialize;
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageOutputFormat,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+use std::io::Cursor;
+use uuid::Uuid;
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+const MAX_BIO_LENGTH: usize = 2000;
+const MAX_LINKS: usize = 5;
+const MAX_LINK_LENGTH: usize = 200;
+const MAX_LOCATION_LENGTH: usize = 100;
+const MAX_PRONOUNS_LENGTH: usize = 30;
+
+const MAX_AVATAR_UPLOAD_BYTES: usize = 5 * 1024 * 1024;
+const AVATAR_SIZE: u32 = 256;
+const AVATAR_THUMBNAIL_SIZE: u32 = 64;
+/// Bounds on decoding an upload, so a small compressed file can't expand to
+/// an enormous image.
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+const MAX_AVATAR_DECODE_BYTES: u64 = 64 * 1024 * 1024;
 
 #[derive(Serialize, Clone)]
 pub struct Profile {
     username: String,
+    display_name: Option<String>,
+    bio: String,
+    bio_html: String,
+    links: Vec<String>,
+    location: Option<String>,
+    pronouns: Option<String>,
+    avatar_url: Option<String>,
+    avatar_thumbnail_url: Option<String>,
     score: i64,
     created_at: DateTime<Local>,
+    is_following: bool,
+    /// Whether the viewer has blocked this user.
+    is_blocked: bool,
+    follower_count: i64,
+    following_count: i64,
+}
+
+#[derive(Deserialize)]
+struct UpdateProfile {
+    display_name: Option<String>,
+    #[serde(default)]
+    bio: String,
+    #[serde(default)]
+    links: Vec<String>,
+    location: Option<String>,
+    pronouns: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ProfileComment {
+    id: i64,
+    pid: Option<i64>,
+    thread_slug: String,
+    thread_title: String,
+    content: String,
+    created_at: DateTime<Local>,
+    is_voted: bool,
+    is_saved: bool,
+    vote_count: i64,
+}
+
+#[derive(Serialize)]
+struct Activity {
+    kind: String,
+    thread_slug: String,
+    thread_title: String,
+    comment_id: Option<i64>,
+    content: Option<String>,
+    created_at: DateTime<Local>,
+}
+
+#[derive(Serialize)]
+struct KarmaPoint {
+    week: DateTime<Local>,
+    gained: i64,
+    total: i64,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    thread_count: i64,
+    comment_count: i64,
+    thread_karma: i64,
+    comment_karma: i64,
+    karma_history: Vec<KarmaPoint>,
 }
 
 pub(crate) fn router() -> Router<AppState> {
     Router::new()
+        .route("/api/profiles/me", get(get_own_profile).put(update_profile))
+        .route("/api/profiles/me/blocks", get(get_blocks))
+        .route(
+            "/api/profiles/me/avatar",
+            put(upload_avatar).layer(DefaultBodyLimit::max(MAX_AVATAR_UPLOAD_BYTES)),
+        )
         .route("/api/profiles/:username", get(get_profile))
         .route(
             "/api/profiles/:username/follow",
             post(follow_user).delete(unfollow_user),
         )
+        .route(
+            "/api/profiles/:username/block",
+            post(block_user).delete(unblock_user),
+        )
+        .route("/api/profiles/:username/followers", get(get_followers))
+        .route("/api/profiles/:username/following", get(get_following))
         .route("/api/profiles/:username/threads", get(get_threads))
+        .route("/api/profiles/:username/comments", get(get_comments))
+        .route("/api/profiles/:username/activity", get(get_activity))
+        .route("/api/profiles/:username/stats", get(get_stats))
 }
 
 async fn get_threads(
@@ -44,16 +148,36 @@ async fn get_threads(
                 title,
                 content,
                 a.created_at as "created_at: DateTime<Local>",
+                a.locked_at is not null as "is_locked!",
+                a.pinned_at is not null as "is_pinned!",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
                 exists(
                     select * 
                     from thread_votes 
                     where user_id = $2
                         and thread_id = a.id
                 ) as "is_voted!",
-                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!"
+                exists(
+                    select * 
+                    from thread_saves 
+                    where user_id = $2
+                        and thread_id = a.id
+                ) as "is_saved!",
+                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.thread_id = a.id
+                        and mentions.comment_id is null
+                    order by mentions.id
+                ) as "mentions!"
             from threads a
             join users b on a.user_id = b.id
             where b.username = $1
+                and a.removed_at is null
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $2)
             order by a.created_at desc
         "#,
         username,
@@ -65,59 +189,295 @@ async fn get_threads(
     Ok(Json(threads))
 }
 
-async fn follow_user(
-    auth_user: AuthUser,
+async fn get_comments(
+    auth_user: MaybeAuthUser,
     State(state): State<AppState>,
     Path(username): Path<String>,
-) -> Result<Json<Profile>> {
-    let mut tx = state.db.begin().await?;
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<ProfileComment>>> {
+    let user_id = user_id(&state.db, &username).await?;
 
-    let user = sqlx::query!(
+    let comments = sqlx::query_as!(
+        ProfileComment,
         r#"
-            select 
-                id, 
-                username,
-                created_at as "created_at: DateTime<Local>"
-            from users 
-            where username = $1
+            select
+                a.id,
+                pid,
+                b.slug as thread_slug,
+                b.title as thread_title,
+                a.content,
+                a.created_at as "created_at: DateTime<Local>",
+                exists(
+                    select *
+                    from comment_votes
+                    where comment_id = a.id
+                        and user_id = $2
+                ) as "is_voted!",
+                exists(
+                    select *
+                    from comment_saves
+                    where comment_id = a.id
+                        and user_id = $2
+                ) as "is_saved!",
+                (select count(*) from comment_votes where comment_id = a.id) as "vote_count!"
+            from comments a
+            join threads b on a.thread_id = b.id
+            where a.user_id = $1
+                and a.removed_at is null
+                and b.removed_at is null
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $2)
+            order by a.created_at desc
+            limit $3
+            offset $4
         "#,
-        username
+        user_id,
+        auth_user.id(),
+        page.limit(),
+        page.offset()
     )
-    .fetch_optional(&mut tx)
-    .await?
-    .ok_or(Error::NotFound)?;
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(comments))
+}
+
+/// Threads and comments the user posted, merged with votes their posts received.
+/// Votes are anonymous, so who cast them isn't shown.
+async fn get_activity(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Activity>>> {
+    let user_id = user_id(&state.db, &username).await?;
+
+    let activity = sqlx::query_as!(
+        Activity,
+        r#"
+            select
+                kind as "kind!",
+                thread_slug as "thread_slug!",
+                thread_title as "thread_title!",
+                comment_id,
+                content,
+                created_at as "created_at!: DateTime<Local>"
+            from (
+                select
+                    'thread' as kind,
+                    slug as thread_slug,
+                    title as thread_title,
+                    null::bigint as comment_id,
+                    content,
+                    created_at
+                from threads
+                where user_id = $1
+                    and removed_at is null
+                    and ((not shadowed and held_at is null) or user_id = $4)
+
+                union all
+
+                select
+                    'comment',
+                    b.slug,
+                    b.title,
+                    a.id,
+                    a.content,
+                    a.created_at
+                from comments a
+                join threads b on a.thread_id = b.id
+                where a.user_id = $1
+                    and a.removed_at is null
+                    and ((not a.shadowed and a.held_at is null) or a.user_id = $4)
+                    and b.removed_at is null
+                    and ((not b.shadowed and b.held_at is null) or b.user_id = $4)
+
+                union all
+
+                select
+                    'thread_vote',
+                    b.slug,
+                    b.title,
+                    null,
+                    null,
+                    a.created_at
+                from thread_votes a
+                join threads b on a.thread_id = b.id
+                where b.user_id = $1
+                    and b.removed_at is null
+                    and ((not b.shadowed and b.held_at is null) or b.user_id = $4)
+
+                union all
 
-    let score = sqlx::query!(
+                select
+                    'comment_vote',
+                    c.slug,
+                    c.title,
+                    b.id,
+                    null,
+                    a.created_at
+                from comment_votes a
+                join comments b on a.comment_id = b.id
+                join threads c on b.thread_id = c.id
+                where b.user_id = $1
+                    and b.removed_at is null
+                    and ((not b.shadowed and b.held_at is null) or b.user_id = $4)
+                    and c.removed_at is null
+                    and ((not c.shadowed and c.held_at is null) or c.user_id = $4)
+            ) activity
+            order by created_at desc
+            limit $2
+            offset $3
+        "#,
+        user_id,
+        page.limit(),
+        page.offset(),
+        auth_user.id()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(activity))
+}
+
+async fn get_stats(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Stats>> {
+    let user_id = user_id(&state.db, &username).await?;
+
+    let counts = sqlx::query!(
         r#"
-            select count(*) as "count!"
-            from thread_votes
-            where user_id = $1
+            select
+                (
+                    select count(*)
+                    from threads
+                    where user_id = $1
+                        and removed_at is null
+                        and ((not shadowed and held_at is null) or user_id = $2)
+                ) as "thread_count!",
+                (
+                    select count(*)
+                    from comments
+                    where user_id = $1
+                        and removed_at is null
+                        and ((not shadowed and held_at is null) or user_id = $2)
+                ) as "comment_count!",
+                (
+                    select count(*)
+                    from thread_votes a
+                    join threads b on a.thread_id = b.id
+                    where b.user_id = $1
+                        and b.removed_at is null
+                        and ((not b.shadowed and b.held_at is null) or b.user_id = $2)
+                ) as "thread_karma!",
+                (
+                    select count(*)
+                    from comment_votes a
+                    join comments b on a.comment_id = b.id
+                    where b.user_id = $1
+                        and b.removed_at is null
+                        and ((not b.shadowed and b.held_at is null) or b.user_id = $2)
+                ) as "comment_karma!"
         "#,
-        user.id
+        user_id,
+        auth_user.id()
     )
-    .fetch_one(&mut tx)
+    .fetch_one(&state.db)
     .await?;
 
-    sqlx::query!(
+    let karma_history = sqlx::query_as!(
+        KarmaPoint,
+        r#"
+            select
+                week as "week!: DateTime<Local>",
+                gained as "gained!",
+                (sum(gained) over (order by week))::bigint as "total!"
+            from (
+                select date_trunc('week', created_at) as week, count(*) as gained
+                from (
+                    select a.created_at
+                    from thread_votes a
+                    join threads b on a.thread_id = b.id
+                    where b.user_id = $1
+                        and b.removed_at is null
+                        and ((not b.shadowed and b.held_at is null) or b.user_id = $2)
+
+                    union all
+
+                    select a.created_at
+                    from comment_votes a
+                    join comments b on a.comment_id = b.id
+                    where b.user_id = $1
+                        and b.removed_at is null
+                        and ((not b.shadowed and b.held_at is null) or b.user_id = $2)
+                ) votes
+                group by week
+            ) weeks
+            order by week
+        "#,
+        user_id,
+        auth_user.id()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(Stats {
+        thread_count: counts.thread_count,
+        comment_count: counts.comment_count,
+        thread_karma: counts.thread_karma,
+        comment_karma: counts.comment_karma,
+        karma_history,
+    }))
+}
+
+async fn follow_user(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Profile>> {
+    let mut tx = state.db.begin().await?;
+
+    let user_id = user_id(&mut tx, &username).await?;
+
+    let followed = sqlx::query!(
         "
             insert into follows(followee_user_id, follower_user_id) 
             values($1, $2) 
             on conflict do nothing
         ",
-        user.id,
+        user_id,
         auth_user.id
     )
     .execute(&mut tx)
     .await
-    .on_constraint("user_cannot_follow_self", |_| Error::Forbidden)?;
+    .on_constraint("user_cannot_follow_self", |_| Error::Forbidden)?
+    .rows_affected()
+        > 0;
+
+    let mut notified = None;
+
+    if followed {
+        notified = notify(
+            &mut tx,
+            NewNotification {
+                user_id,
+                actor_user_id: auth_user.id,
+                kind: NotificationKind::Follow,
+                thread_id: None,
+                comment_id: None,
+            },
+        )
+        .await?;
+    }
+
+    let profile = load_profile(&mut tx, &username, Some(auth_user.id)).await?;
 
     tx.commit().await?;
 
-    Ok(Json(Profile {
-        username: user.username,
-        score: score.count,
-        created_at: user.created_at,
-    }))
+    state.events.notify(notified);
+
+    Ok(Json(profile))
 }
 
 async fn unfollow_user(
@@ -127,85 +487,583 @@ async fn unfollow_user(
 ) -> Result<Json<Profile>> {
     let mut tx = state.db.begin().await?;
 
-    let user = sqlx::query!(
-        r#"
-            select 
-                id, 
-                username,
-                created_at as "created_at: DateTime<Local>"
-            from users 
-            where username = $1
-        "#,
-        username
-    )
-    .fetch_optional(&mut tx)
-    .await?
-    .ok_or(Error::NotFound)?;
+    let user_id = user_id(&mut tx, &username).await?;
 
-    let score = sqlx::query!(
-        r#"
-            select count(*) as "count!"
-            from thread_votes
-            where user_id = $1
-        "#,
-        user.id
+    sqlx::query!(
+        "
+            delete from follows 
+            where followee_user_id = $1 and follower_user_id = $2
+        ",
+        user_id,
+        auth_user.id
     )
-    .fetch_one(&mut tx)
+    .execute(&mut tx)
     .await?;
 
+    let profile = load_profile(&mut tx, &username, Some(auth_user.id)).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(profile))
+}
+
+/// Blocks a user, which also unfollows them in both directions. Blocked users
+/// can't reply to or mention the blocker, and their posts are hidden from the
+/// blocker.
+async fn block_user(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Profile>> {
+    let mut tx = state.db.begin().await?;
+
+    let user_id = user_id(&mut tx, &username).await?;
+
     sqlx::query!(
         "
-            delete from follows 
-            where followee_user_id = $1 and follower_user_id = $2
+            insert into blocks(blocker_user_id, blocked_user_id)
+            values($1, $2)
+            on conflict do nothing
+        ",
+        auth_user.id,
+        user_id
+    )
+    .execute(&mut tx)
+    .await
+    .on_constraint("user_cannot_block_self", |_| Error::Forbidden)?;
+
+    sqlx::query!(
+        "
+            delete from follows
+            where (followee_user_id = $1 and follower_user_id = $2)
+                or (followee_user_id = $2 and follower_user_id = $1)
         ",
-        user.id,
+        user_id,
         auth_user.id
     )
     .execute(&mut tx)
     .await?;
 
+    let profile = load_profile(&mut tx, &username, Some(auth_user.id)).await?;
+
     tx.commit().await?;
 
-    Ok(Json(Profile {
-        username: user.username,
-        score: score.count,
-        created_at: user.created_at,
-    }))
+    Ok(Json(profile))
+}
+
+async fn unblock_user(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Json<Profile>> {
+    let mut tx = state.db.begin().await?;
+
+    let user_id = user_id(&mut tx, &username).await?;
+
+    sqlx::query!(
+        "
+            delete from blocks
+            where blocker_user_id = $1
+                and blocked_user_id = $2
+        ",
+        auth_user.id,
+        user_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let profile = load_profile(&mut tx, &username, Some(auth_user.id)).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(profile))
+}
+
+/// The users the caller has blocked, most recent first.
+async fn get_blocks(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Profile>>> {
+    let blocked = sqlx::query_as!(
+        Profile,
+        r#"
+            select
+                username,
+                display_name,
+                bio,
+                bio_html,
+                links,
+                location,
+                pronouns,
+                avatar_url,
+                avatar_thumbnail_url,
+                (select count(*) from thread_votes where user_id = a.id) as "score!",
+                a.created_at as "created_at: DateTime<Local>",
+                false as "is_following!",
+                true as "is_blocked!",
+                (select count(*) from follows where followee_user_id = a.id) as "follower_count!",
+                (select count(*) from follows where follower_user_id = a.id) as "following_count!"
+            from users a
+            join blocks b on b.blocked_user_id = a.id
+            where b.blocker_user_id = $1
+            order by b.created_at desc
+            limit $2
+            offset $3
+        "#,
+        auth_user.id,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(blocked))
 }
 
 async fn get_profile(
+    auth_user: MaybeAuthUser,
     State(state): State<AppState>,
     Path(username): Path<String>,
 ) -> Result<Json<Profile>> {
-    let user = sqlx::query!(
+    Ok(Json(
+        load_profile(&state.db, &username, auth_user.id()).await?,
+    ))
+}
+
+async fn get_own_profile(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<Profile>> {
+    let username = sqlx::query_scalar!(
+        "
+            select username
+            from users
+            where id = $1
+        ",
+        auth_user.id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(
+        load_profile(&state.db, &username, Some(auth_user.id)).await?,
+    ))
+}
+
+async fn update_profile(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    ValidJson(req): ValidJson<UpdateProfile>,
+) -> Result<Json<Profile>> {
+    let req = req.normalize();
+
+    let bio_html = render_markdown(&req.bio);
+
+    let username = sqlx::query_scalar!(
+        "
+            update users
+            set display_name = $2,
+                bio = $3,
+                bio_html = $4,
+                links = $5,
+                location = $6,
+                pronouns = $7
+            where id = $1
+            returning username
+        ",
+        auth_user.id,
+        req.display_name,
+        req.bio,
+        bio_html,
+        &req.links,
+        req.location,
+        req.pronouns
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(
+        load_profile(&state.db, &username, Some(auth_user.id)).await?,
+    ))
+}
+
+/// Accepts a multipart upload with an `avatar` image field, stored as a square
+/// avatar and a smaller thumbnail.
+async fn upload_avatar(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Json<Profile>> {
+    let mut upload = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| Error::unprocessable_entity([("avatar", "is not a valid upload")]))?
+    {
+        if field.name() == Some("avatar") {
+            upload =
+                Some(field.bytes().await.map_err(|_| {
+                    Error::unprocessable_entity([("avatar", "is not a valid upload")])
+                })?);
+            break;
+        }
+    }
+
+    let upload = upload.ok_or_else(|| Error::unprocessable_entity([("avatar", "is required")]))?;
+
+    let (avatar, thumbnail) = tokio::task::spawn_blocking(move || resize_avatar(&upload))
+        .await
+        .context("Avatar resize task failed")??;
+
+    let key = format!("avatars/{}/{}", auth_user.id, Uuid::new_v4());
+
+    let avatar_url = state
+        .storage
+        .put(&format!("{}-{}.png", key, AVATAR_SIZE), "image/png", avatar)
+        .await?;
+
+    let avatar_thumbnail_url = state
+        .storage
+        .put(
+            &format!("{}-{}.png", key, AVATAR_THUMBNAIL_SIZE),
+            "image/png",
+            thumbnail,
+        )
+        .await?;
+
+    let updated = sqlx::query!(
+        "
+            with old as (
+                select avatar_url, avatar_thumbnail_url
+                from users
+                where id = $1
+                for update
+            )
+            update users
+            set avatar_url = $2,
+                avatar_thumbnail_url = $3
+            from old
+            where id = $1
+            returning username, old.avatar_url as old_avatar_url, old.avatar_thumbnail_url as old_avatar_thumbnail_url
+        ",
+        auth_user.id,
+        avatar_url,
+        avatar_thumbnail_url
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    // The new avatar is already in place, so a leftover file is only logged.
+    for url in [updated.old_avatar_url, updated.old_avatar_thumbnail_url]
+        .into_iter()
+        .flatten()
+    {
+        if let Err(e) = state.storage.delete(&url).await {
+            tracing::warn!("Failed to delete old avatar {}: {:?}", url, e);
+        }
+    }
+
+    Ok(Json(
+        load_profile(&state.db, &updated.username, Some(auth_user.id)).await?,
+    ))
+}
+
+async fn get_followers(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Profile>>> {
+    let user_id = user_id(&state.db, &username).await?;
+
+    let followers = sqlx::query_as!(
+        Profile,
         r#"
-            select 
-                id, 
+            select
                 username,
-                created_at as "created_at: DateTime<Local>"
-            from users 
-            where username = $1
+                display_name,
+                bio,
+                bio_html,
+                links,
+                location,
+                pronouns,
+                avatar_url,
+                avatar_thumbnail_url,
+                (select count(*) from thread_votes where user_id = a.id) as "score!",
+                a.created_at as "created_at: DateTime<Local>",
+                exists(
+                    select *
+                    from follows
+                    where followee_user_id = a.id
+                        and follower_user_id = $2
+                ) as "is_following!",
+                exists(
+                    select *
+                    from blocks
+                    where blocked_user_id = a.id
+                        and blocker_user_id = $2
+                ) as "is_blocked!",
+                (select count(*) from follows where followee_user_id = a.id) as "follower_count!",
+                (select count(*) from follows where follower_user_id = a.id) as "following_count!"
+            from users a
+            join follows b on b.follower_user_id = a.id
+            where b.followee_user_id = $1
+            order by b.created_at desc
+            limit $3
+            offset $4
+        "#,
+        user_id,
+        auth_user.id(),
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(followers))
+}
+
+async fn get_following(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Profile>>> {
+    let user_id = user_id(&state.db, &username).await?;
+
+    let following = sqlx::query_as!(
+        Profile,
+        r#"
+            select
+                username,
+                display_name,
+                bio,
+                bio_html,
+                links,
+                location,
+                pronouns,
+                avatar_url,
+                avatar_thumbnail_url,
+                (select count(*) from thread_votes where user_id = a.id) as "score!",
+                a.created_at as "created_at: DateTime<Local>",
+                exists(
+                    select *
+                    from follows
+                    where followee_user_id = a.id
+                        and follower_user_id = $2
+                ) as "is_following!",
+                exists(
+                    select *
+                    from blocks
+                    where blocked_user_id = a.id
+                        and blocker_user_id = $2
+                ) as "is_blocked!",
+                (select count(*) from follows where followee_user_id = a.id) as "follower_count!",
+                (select count(*) from follows where follower_user_id = a.id) as "following_count!"
+            from users a
+            join follows b on b.followee_user_id = a.id
+            where b.follower_user_id = $1
+            order by b.created_at desc
+            limit $3
+            offset $4
         "#,
+        user_id,
+        auth_user.id(),
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(following))
+}
+
+async fn user_id(db: impl PgExecutor<'_>, username: &str) -> Result<i64> {
+    sqlx::query_scalar!(
+        "
+            select id
+            from users
+            where username = $1
+        ",
         username
     )
-    .fetch_optional(&state.db)
+    .fetch_optional(db)
     .await?
-    .ok_or(Error::NotFound)?;
+    .ok_or(Error::NotFound)
+}
 
-    let score = sqlx::query!(
+async fn load_profile(
+    db: impl PgExecutor<'_>,
+    username: &str,
+    viewer_id: Option<i64>,
+) -> Result<Profile> {
+    sqlx::query_as!(
+        Profile,
         r#"
-            select count(*) as "count!"
-            from thread_votes
-            where user_id = $1
+            select
+                username,
+                display_name,
+                bio,
+                bio_html,
+                links,
+                location,
+                pronouns,
+                avatar_url,
+                avatar_thumbnail_url,
+                (select count(*) from thread_votes where user_id = a.id) as "score!",
+                a.created_at as "created_at: DateTime<Local>",
+                exists(
+                    select *
+                    from follows
+                    where followee_user_id = a.id
+                        and follower_user_id = $2
+                ) as "is_following!",
+                exists(
+                    select *
+                    from blocks
+                    where blocked_user_id = a.id
+                        and blocker_user_id = $2
+                ) as "is_blocked!",
+                (select count(*) from follows where followee_user_id = a.id) as "follower_count!",
+                (select count(*) from follows where follower_user_id = a.id) as "following_count!"
+            from users a
+            where username = $1
         "#,
-        user.id
+        username,
+        viewer_id
     )
-    .fetch_one(&state.db)
-    .await?;
+    .fetch_optional(db)
+    .await?
+    .ok_or(Error::NotFound)
+}
 
-    Ok(Json(Profile {
-        username: user.username,
-        score: score.count,
-        created_at: user.created_at,
-    }))
+impl UpdateProfile {
+    /// Trims every field, treating blank optional fields as unset.
+    fn normalize(self) -> Self {
+        fn optional(value: Option<String>) -> Option<String> {
+            value
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        }
+
+        Self {
+            display_name: optional(self.display_name),
+            bio: self.bio.trim().to_string(),
+            links: self
+                .links
+                .into_iter()
+                .map(|link| link.trim().to_string())
+                .filter(|link| !link.is_empty())
+                .collect(),
+            location: optional(self.location),
+            pronouns: optional(self.pronouns),
+        }
+    }
+}
+
+impl Validate for UpdateProfile {
+    /// Checks the fields as `normalize` will store them.
+    fn check(&self, violations: &mut Violations) {
+        check_optional(
+            violations,
+            "display_name",
+            &self.display_name,
+            MAX_DISPLAY_NAME_LENGTH,
+        );
+
+        violations
+            .field("bio", self.bio.trim())
+            .max_chars(MAX_BIO_LENGTH);
+
+        let links = self
+            .links
+            .iter()
+            .map(|link| link.trim())
+            .filter(|link| !link.is_empty())
+            .collect::<Vec<_>>();
+
+        if links.len() > MAX_LINKS {
+            violations.add("links", format!("must have at most {} links", MAX_LINKS));
+        }
+
+        for link in links {
+            violations
+                .field("links", link)
+                .rule(
+                    |link| link.starts_with("https://") || link.starts_with("http://"),
+                    format!("{} must be an http or https URL", link),
+                )
+                .rule(
+                    |link| link.len() <= MAX_LINK_LENGTH,
+                    format!("must each be at most {} characters", MAX_LINK_LENGTH),
+                );
+        }
+
+        check_optional(violations, "location", &self.location, MAX_LOCATION_LENGTH);
+        check_optional(violations, "pronouns", &self.pronouns, MAX_PRONOUNS_LENGTH);
+    }
+}
+
+fn check_optional(
+    violations: &mut Violations,
+    name: &'static str,
+    value: &Option<String>,
+    max: usize,
+) {
+    if let Some(value) = value {
+        violations.field(name, value.trim()).max_chars(max);
+    }
+}
+
+/// Renders user-supplied markdown to HTML, stripping anything unsafe to embed.
+fn render_markdown(source: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(source));
+    ammonia::clean(&html)
+}
+
+fn resize_avatar(bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let unsupported = || Error::unprocessable_entity([("avatar", "is not a supported image")]);
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    limits.max_alloc = Some(MAX_AVATAR_DECODE_BYTES);
+
+    let mut reader = Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|_| unsupported())?;
+    reader.limits(limits);
+
+    let image = reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(_) => Error::unprocessable_entity([(
+            "avatar",
+            format!(
+                "must be at most {}x{} pixels",
+                MAX_AVATAR_DIMENSION, MAX_AVATAR_DIMENSION
+            ),
+        )]),
+        _ => unsupported(),
+    })?;
+
+    let avatar = image.resize_to_fill(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+    let thumbnail = avatar.resize_to_fill(
+        AVATAR_THUMBNAIL_SIZE,
+        AVATAR_THUMBNAIL_SIZE,
+        FilterType::Lanczos3,
+    );
+
+    Ok((encode_png(&avatar)?, encode_png(&thumbnail)?))
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+
+    image
+        .write_to(&mut bytes, ImageOutputFormat::Png)
+        .context("Failed to encode avatar")?;
+
+    Ok(bytes.into_inner())
 }
@@ -0,0 +1,290 @@
+use super::{AppState, Error, Pagination, Result};
+use crate::auth::MaybeAuthUser;
+use crate::automod::PostKind;
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+const DEFAULT_SUGGESTIONS: i64 = 8;
+const MAX_SUGGESTIONS: i64 = 20;
+/// Longer prefixes are cut to this many characters.
+const MAX_PREFIX_LENGTH: usize = 64;
+/// The SQLSTATE of a statement cancelled by `statement_timeout`.
+const QUERY_CANCELED: &str = "57014";
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    /// Words to look for. Supports `"quoted phrases"`, `-excluded` words and `or`.
+    q: String,
+    /// Only posts by this user.
+    author: Option<String>,
+    /// Only posts made on or after this day.
+    from: Option<NaiveDate>,
+    /// Only posts made on or before this day.
+    to: Option<NaiveDate>,
+    #[serde(rename = "type")]
+    kind: Option<PostKind>,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    kind: String,
+    thread_slug: String,
+    /// HTML-escaped, with matches wrapped in `<mark>`.
+    thread_title: String,
+    comment_id: Option<i64>,
+    username: String,
+    /// The best matching fragments of the content, HTML-escaped, with matches
+    /// wrapped in `<mark>`.
+    snippet: String,
+    rank: f32,
+    created_at: DateTime<Local>,
+}
+
+#[derive(Deserialize)]
+struct AutocompleteQuery {
+    prefix: String,
+    limit: Option<i64>,
+}
+
+impl AutocompleteQuery {
+    /// The prefix, trimmed and cut to length, with `LIKE` wildcards escaped.
+    fn pattern(&self) -> String {
+        let mut pattern = String::new();
+
+        for c in self.prefix.trim().chars().take(MAX_PREFIX_LENGTH) {
+            if matches!(c, '\\' | '%' | '_') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+
+        pattern
+    }
+
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_SUGGESTIONS)
+            .clamp(1, MAX_SUGGESTIONS)
+    }
+}
+
+#[derive(Serialize)]
+struct UserSuggestion {
+    username: String,
+    avatar_thumbnail_url: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThreadSuggestion {
+    slug: String,
+    title: String,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/search", get(search))
+        .route("/api/autocomplete/users", get(autocomplete_users))
+        .route("/api/autocomplete/threads", get(autocomplete_threads))
+}
+
+/// Threads and comments matching a query, best matches first.
+async fn search(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<SearchResult>>> {
+    if query.q.trim().is_empty() {
+        return Err(Error::unprocessable_entity([("q", "can't be empty")]));
+    }
+
+    let results = sqlx::query_as!(
+        SearchResult,
+        r#"
+            with query as (
+                select websearch_to_tsquery('english', $1) as query
+            )
+            select
+                kind as "kind!",
+                thread_slug as "thread_slug!",
+                ts_headline(
+                    'english',
+                    html_escape(thread_title),
+                    query,
+                    'HighlightAll=true, StartSel=<mark>, StopSel=</mark>'
+                ) as "thread_title!",
+                comment_id,
+                username as "username!",
+                ts_headline(
+                    'english',
+                    html_escape(content),
+                    query,
+                    'MaxFragments=2, MaxWords=30, MinWords=10, StartSel=<mark>, StopSel=</mark>'
+                ) as "snippet!",
+                rank as "rank!",
+                created_at as "created_at!: DateTime<Local>"
+            from (
+                select
+                    'thread' as kind,
+                    a.slug as thread_slug,
+                    a.title as thread_title,
+                    null::bigint as comment_id,
+                    a.user_id,
+                    b.username,
+                    a.content,
+                    ts_rank_cd(a.search, c.query) as rank,
+                    a.created_at
+                from threads a
+                join users b on a.user_id = b.id
+                cross join query c
+                where a.search @@ c.query
+                    and $5::text is distinct from 'comment'
+                    and a.removed_at is null
+                    and ((not a.shadowed and a.held_at is null) or a.user_id = $6)
+                union all
+                select
+                    'comment' as kind,
+                    c.slug as thread_slug,
+                    c.title as thread_title,
+                    a.id as comment_id,
+                    a.user_id,
+                    b.username,
+                    a.content,
+                    ts_rank_cd(a.search, d.query) as rank,
+                    a.created_at
+                from comments a
+                join users b on a.user_id = b.id
+                join threads c on a.thread_id = c.id
+                cross join query d
+                where a.search @@ d.query
+                    and $5::text is distinct from 'thread'
+                    and a.removed_at is null
+                    and c.removed_at is null
+                    and ((not a.shadowed and a.held_at is null) or a.user_id = $6)
+                    and ((not c.shadowed and c.held_at is null) or c.user_id = $6)
+            ) results
+            cross join query
+            where ($2::text is null or username = $2)
+                and ($3::date is null or created_at >= $3::date)
+                and ($4::date is null or created_at < $4::date + 1)
+                and not exists(
+                    select *
+                    from blocks
+                    where blocker_user_id = $6
+                        and blocked_user_id = results.user_id
+                )
+            order by rank desc, created_at desc
+            limit $7
+            offset $8
+        "#,
+        query.q,
+        query.author,
+        query.from,
+        query.to,
+        query.kind.map(|kind| kind.as_str()),
+        auth_user.id(),
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(results))
+}
+
+/// Usernames starting with a prefix, shortest first.
+async fn autocomplete_users(
+    State(state): State<AppState>,
+    Query(query): Query<AutocompleteQuery>,
+) -> Result<Json<Vec<UserSuggestion>>> {
+    let pattern = query.pattern();
+
+    if pattern.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    set_timeout(&mut tx).await?;
+
+    let users = sqlx::query_as!(
+        UserSuggestion,
+        r#"
+            select username, avatar_thumbnail_url
+            from users
+            where lower(username) like lower($1) || '%'
+            order by length(username), username
+            limit $2
+        "#,
+        pattern,
+        query.limit()
+    )
+    .fetch_all(&mut tx)
+    .await;
+
+    Ok(Json(or_timed_out(users)?))
+}
+
+/// Threads with titles containing a prefix, those starting with it first.
+async fn autocomplete_threads(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Query(query): Query<AutocompleteQuery>,
+) -> Result<Json<Vec<ThreadSuggestion>>> {
+    let pattern = query.pattern();
+
+    if pattern.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    set_timeout(&mut tx).await?;
+
+    let threads = sqlx::query_as!(
+        ThreadSuggestion,
+        r#"
+            select slug, title
+            from threads
+            where lower(title) like '%' || lower($1) || '%'
+                and removed_at is null
+                and ((not shadowed and held_at is null) or user_id = $2)
+            order by
+                lower(title) like lower($1) || '%' desc,
+                created_at desc
+            limit $3
+        "#,
+        pattern,
+        auth_user.id(),
+        query.limit()
+    )
+    .fetch_all(&mut tx)
+    .await;
+
+    Ok(Json(or_timed_out(threads)?))
+}
+
+/// Cancels suggestion queries that take long enough to make typing feel slow.
+async fn set_timeout(tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+    sqlx::query!("set local statement_timeout = '250ms'")
+        .execute(tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Treats suggestions that took too long as there being none.
+fn or_timed_out<T>(result: Result<Vec<T>, sqlx::Error>) -> Result<Vec<T>> {
+    match result {
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(QUERY_CANCELED) => {
+            Ok(Vec::new())
+        }
+        result => Ok(result?),
+    }
+}
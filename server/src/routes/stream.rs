@@ -0,0 +1,168 @@
+use super::comments::Comment;
+use super::messages::Message;
+use super::threads::{Thread, VoteCount};
+use super::{AppState, Error, Result};
+use crate::auth::MaybeAuthUser;
+use crate::notifications::NotificationCreated;
+use axum::{
+    extract::{Query, State},
+    response::sse::{self, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{BroadcastStream, WatchStream},
+    Stream, StreamExt,
+};
+
+const EVENT_BUS_CAPACITY: usize = 1024;
+/// How long clients are asked to wait before reconnecting when the server shuts down.
+const SHUTDOWN_RETRY: Duration = Duration::from_secs(5);
+
+/// What an event is about, used to route it to the streams subscribed to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Topic {
+    /// The global thread listing.
+    Listing,
+    /// A single thread and its comments, by slug.
+    Thread(String),
+    /// A user's notifications and direct messages, by user id.
+    User(i64),
+}
+
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum Event {
+    Thread(Thread),
+    Comment(Comment),
+    ThreadVotes {
+        slug: String,
+        #[serde(flatten)]
+        votes: VoteCount,
+    },
+    CommentVotes {
+        id: i64,
+        #[serde(flatten)]
+        votes: VoteCount,
+    },
+    Notification(NotificationCreated),
+    Message(Message),
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Thread(_) => "thread",
+            Self::Comment(_) => "comment",
+            Self::ThreadVotes { .. } => "thread_votes",
+            Self::CommentVotes { .. } => "comment_votes",
+            Self::Notification(_) => "notification",
+            Self::Message(_) => "message",
+        }
+    }
+}
+
+/// In-process fan-out of events to every open stream.
+#[derive(Clone)]
+pub(crate) struct EventBus {
+    tx: broadcast::Sender<(Topic, Event)>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn publish(&self, topic: Topic, event: Event) {
+        // Sending only fails when nobody is subscribed, which is fine to ignore.
+        let _ = self.tx.send((topic, event));
+    }
+
+    pub fn notify(&self, created: impl IntoIterator<Item = NotificationCreated>) {
+        for notification in created {
+            self.publish(
+                Topic::User(notification.user_id),
+                Event::Notification(notification),
+            );
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<(Topic, Event)> {
+        self.tx.subscribe()
+    }
+}
+
+#[derive(Deserialize)]
+struct Subscription {
+    thread: Option<String>,
+    #[serde(default)]
+    listing: bool,
+    #[serde(default)]
+    notifications: bool,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new().route("/api/stream", get(stream))
+}
+
+async fn stream(
+    auth_user: MaybeAuthUser,
+    State(state): State<AppState>,
+    Query(sub): Query<Subscription>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>> {
+    let mut topics = Vec::new();
+
+    if let Some(slug) = sub.thread {
+        topics.push(Topic::Thread(slug));
+    }
+
+    if sub.listing {
+        topics.push(Topic::Listing);
+    }
+
+    if sub.notifications {
+        topics.push(Topic::User(auth_user.id().ok_or(Error::Unauthorized)?));
+    }
+
+    if topics.is_empty() {
+        return Err(Error::unprocessable_entity([(
+            "subscription",
+            "must include a thread, listing or notifications",
+        )]));
+    }
+
+    let events = BroadcastStream::new(state.events.subscribe()).filter_map(move |msg| {
+        match msg {
+            Ok((topic, event)) if topics.contains(&topic) => sse::Event::default()
+                .event(event.name())
+                .json_data(&event)
+                .ok()
+                .map(Ok),
+            Ok(_) => None,
+            // The client fell behind and missed events, so tell it to refetch.
+            Err(_) => Some(Ok(sse::Event::default().event("lagged").data(""))),
+        }
+    });
+
+    // Ends the stream when the server starts shutting down, so it doesn't hold
+    // up draining, with a last event telling the client to reconnect later.
+    let closing = WatchStream::new(state.shutdown.subscribe())
+        .filter(|closing| *closing)
+        .map(|_| None);
+
+    let events = events
+        .map(Some)
+        .merge(closing)
+        .map_while(|event| event)
+        .chain(tokio_stream::once(Ok(sse::Event::default()
+            .event("shutdown")
+            .data("")
+            .retry(SHUTDOWN_RETRY))));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -1,7 +1,14 @@
-use super::{AppState, Error, Result, ResultExt};
-use crate::auth::{AuthUser, MaybeAuthUser};
+use super::stream::{Event, Topic};
+use super::{AppState, Error, Pagination, Result, ResultExt};
+use crate::auth::{AuthUser, MaybeAuthUser, Role};
+use crate::automod::{self, Post, PostKind};
+use crate::mentions;
+use crate::mod_log::{self, ModAction, ModLogEntry};
+use crate::notifications;
+use crate::rate_limit::{self, Action, ClientIp};
+use crate::validate::{ValidJson, Validate, Violations};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     routing::{get, post},
     Json, Router,
 };
@@ -9,13 +16,24 @@ use chrono::{DateTime, Local};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
+const MAX_TITLE_LENGTH: usize = 300;
+const MAX_CONTENT_LENGTH: usize = 40_000;
+
 #[derive(Deserialize)]
 struct NewThread {
     title: String,
     content: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Deserialize)]
+struct ThreadEdit {
+    title: Option<String>,
+    content: Option<String>,
+    /// Recorded in the moderation log when a moderator edits someone else's thread.
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Thread {
     pub author_id: i64,
     pub username: String,
@@ -23,26 +41,40 @@ pub struct Thread {
     pub title: String,
     pub content: String,
     pub created_at: DateTime<Local>,
+    pub is_locked: bool,
+    pub is_pinned: bool,
+    pub edited_at: Option<DateTime<Local>>,
+    /// Held for review by the automoderator; only the author sees it until approved.
+    pub is_held: bool,
     pub is_voted: bool,
+    pub is_saved: bool,
     pub vote_count: i64,
+    /// The users the content mentions, which the client links to.
+    pub mentions: Vec<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize)]
 struct Listing {
     threads: Vec<Thread>,
 }
 
-#[derive(Serialize)]
-struct VoteCount {
-    count: i64,
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct VoteCount {
+    pub count: i64,
 }
 
 pub(crate) fn router() -> Router<AppState> {
     Router::new()
         .route("/api/threads", post(create_thread).get(get_listing))
-        .route("/api/threads/:slug", get(get_thread))
+        .route("/api/threads/:slug", get(get_thread).put(edit_thread))
         .route("/api/threads/:slug/vote", post(vote).get(get_votes))
         .route("/api/threads/:slug/unvote", post(unvote_thread))
+        .route(
+            "/api/threads/:slug/save",
+            post(save_thread).delete(unsave_thread),
+        )
+        .route("/api/feed", get(get_feed))
 }
 
 async fn get_votes(
@@ -73,30 +105,31 @@ async fn get_votes(
 async fn vote(
     auth_user: AuthUser,
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Path(slug): Path<String>,
 ) -> Result<Json<VoteCount>> {
-    let thread_id = sqlx::query_scalar!(
-        "
-            select id
-            from threads
-            where slug = $1
-        ",
-        slug
-    )
-    .fetch_one(&state.db)
-    .await?;
+    let permit = rate_limit::check(&state, auth_user.id, ip, Action::Vote).await?;
 
-    sqlx::query!(
+    let thread = votable_thread(&state, &slug, auth_user.id).await?;
+
+    permit.record(&state.rate_limiter)?;
+
+    let inserted = sqlx::query!(
         "
             insert into thread_votes(thread_id, user_id)
             values($1, $2)
             on conflict do nothing
         ",
-        thread_id,
+        thread.id,
         auth_user.id
     )
     .execute(&state.db)
-    .await?;
+    .await?
+    .rows_affected();
+
+    if inserted > 0 {
+        metrics::increment_counter!("votes_created_total", "kind" => "thread");
+    }
 
     let count = sqlx::query_as!(
         VoteCount,
@@ -105,29 +138,29 @@ async fn vote(
             from thread_votes
             where thread_id = $1
         "#,
-        thread_id
+        thread.id
     )
     .fetch_one(&state.db)
     .await?;
 
+    if thread.is_visible {
+        publish_votes(&state, slug, &count);
+    }
+
     Ok(Json(count))
 }
 
 async fn unvote_thread(
     auth_user: AuthUser,
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Path(slug): Path<String>,
 ) -> Result<Json<VoteCount>> {
-    let thread_id = sqlx::query_scalar!(
-        "
-            select id
-            from threads
-            where slug = $1
-        ",
-        slug
-    )
-    .fetch_one(&state.db)
-    .await?;
+    let permit = rate_limit::check(&state, auth_user.id, ip, Action::Vote).await?;
+
+    let thread = votable_thread(&state, &slug, auth_user.id).await?;
+
+    permit.record(&state.rate_limiter)?;
 
     sqlx::query!(
         "
@@ -135,7 +168,7 @@ async fn unvote_thread(
             where thread_id = $1
             and user_id = $2
         ",
-        thread_id,
+        thread.id,
         auth_user.id
     )
     .execute(&state.db)
@@ -148,14 +181,71 @@ async fn unvote_thread(
             from thread_votes
             where thread_id = $1
         "#,
-        thread_id
+        thread.id
     )
     .fetch_one(&state.db)
     .await?;
 
+    if thread.is_visible {
+        publish_votes(&state, slug, &count);
+    }
+
     Ok(Json(count))
 }
 
+async fn save_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<()> {
+    let thread_id = sqlx::query_scalar!(
+        "
+            select id
+            from threads
+            where slug = $1
+                and removed_at is null
+        ",
+        slug
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    sqlx::query!(
+        "
+            insert into thread_saves(thread_id, user_id)
+            values($1, $2)
+            on conflict do nothing
+        ",
+        thread_id,
+        auth_user.id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+async fn unsave_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<()> {
+    sqlx::query!(
+        "
+            delete from thread_saves
+            where thread_id = (select id from threads where slug = $1)
+                and user_id = $2
+        ",
+        slug,
+        auth_user.id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
 async fn get_listing(
     auth_user: MaybeAuthUser,
     State(state): State<AppState>,
@@ -170,16 +260,42 @@ async fn get_listing(
                 title, 
                 content, 
                 a.created_at as "created_at: DateTime<Local>",
+                a.locked_at is not null as "is_locked!",
+                a.pinned_at is not null as "is_pinned!",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
                 exists(
                     select * 
                     from thread_votes 
                     where user_id = $1 
                     and thread_id = a.id
                 ) as "is_voted!",
-                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!"
+                exists(
+                    select * 
+                    from thread_saves 
+                    where user_id = $1 
+                    and thread_id = a.id
+                ) as "is_saved!",
+                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.thread_id = a.id
+                        and mentions.comment_id is null
+                    order by mentions.id
+                ) as "mentions!"
             from threads a
             join users b on a.user_id = b.id
-            order by a.created_at desc
+            where a.removed_at is null
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $1)
+                and not exists(
+                    select *
+                    from blocks
+                    where blocker_user_id = $1
+                        and blocked_user_id = a.user_id
+                )
+            order by a.pinned_at desc nulls last, a.created_at desc
         "#,
         auth_user.id()
     )
@@ -189,6 +305,67 @@ async fn get_listing(
     Ok(Json(threads))
 }
 
+async fn get_feed(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Thread>>> {
+    let threads = sqlx::query_as!(
+        Thread,
+        r#"
+            select
+                a.user_id as author_id,
+                b.username,
+                slug,
+                title,
+                content,
+                a.created_at as "created_at: DateTime<Local>",
+                a.locked_at is not null as "is_locked!",
+                a.pinned_at is not null as "is_pinned!",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                exists(
+                    select *
+                    from thread_votes
+                    where user_id = $1
+                    and thread_id = a.id
+                ) as "is_voted!",
+                exists(
+                    select *
+                    from thread_saves
+                    where user_id = $1
+                    and thread_id = a.id
+                ) as "is_saved!",
+                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.thread_id = a.id
+                        and mentions.comment_id is null
+                    order by mentions.id
+                ) as "mentions!"
+            from threads a
+            join users b on a.user_id = b.id
+            join follows c on c.followee_user_id = a.user_id
+            where c.follower_user_id = $1
+                and a.removed_at is null
+                and not a.shadowed
+                and a.held_at is null
+            order by a.created_at desc
+            limit $2
+            offset $3
+        "#,
+        auth_user.id,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(threads))
+}
+
 async fn get_thread(
     auth_user: MaybeAuthUser,
     State(state): State<AppState>,
@@ -204,16 +381,36 @@ async fn get_thread(
                 title, 
                 content, 
                 a.created_at as "created_at: DateTime<Local>",
+                a.locked_at is not null as "is_locked!",
+                a.pinned_at is not null as "is_pinned!",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
                 exists(
                     select * 
                     from thread_votes 
                     where user_id = $1 
                     and thread_id = a.id
                 ) as "is_voted!",
-                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!"
+                exists(
+                    select * 
+                    from thread_saves 
+                    where user_id = $1 
+                    and thread_id = a.id
+                ) as "is_saved!",
+                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.thread_id = a.id
+                        and mentions.comment_id is null
+                    order by mentions.id
+                ) as "mentions!"
             from threads a
             join users b on a.user_id = b.id
             where slug = $2
+                and a.removed_at is null
+                and ((not a.shadowed and a.held_at is null) or a.user_id = $1)
         "#,
         auth_user.id(),
         slug
@@ -228,26 +425,75 @@ async fn get_thread(
 async fn create_thread(
     auth_user: AuthUser,
     State(state): State<AppState>,
-    Json(req): Json<NewThread>,
+    ClientIp(ip): ClientIp,
+    ValidJson(req): ValidJson<NewThread>,
 ) -> Result<Json<Thread>> {
+    let permit = rate_limit::check(&state, auth_user.id, ip, Action::Thread).await?;
+    rate_limit::check_duplicate(&state.db, auth_user.id, &req.content).await?;
+
     let slug = slugify(&req.title);
 
-    sqlx::query!(
+    let mut tx = state.db.begin().await?;
+
+    let verdict = state
+        .automod
+        .check(
+            &mut tx,
+            auth_user.id,
+            Post {
+                kind: PostKind::Thread,
+                title: Some(&req.title),
+                content: &req.content,
+            },
+        )
+        .await?;
+
+    let inserted = sqlx::query!(
         r#"
-            insert into threads(user_id, title, slug, content)
-            values($1, $2, $3, $4)
+            insert into threads(user_id, title, slug, content, shadowed, held_at)
+            values(
+                $1,
+                $2,
+                $3,
+                $4,
+                exists(
+                    select *
+                    from active_bans
+                    where user_id = $1
+                        and kind = 'shadowban'
+                ),
+                case when $5 then now() end
+            )
+            returning id, shadowed, held_at is not null as "is_held!"
         "#,
         auth_user.id,
         req.title,
         slug,
-        req.content
+        req.content,
+        verdict.is_held()
     )
-    .execute(&state.db)
+    .fetch_one(&mut tx)
     .await
     .on_constraint("threads_slug_key", |_| {
         Error::unprocessable_entity([("slug", format!("duplicate thread slug: {}", slug))])
     })?;
 
+    automod::report(&mut tx, PostKind::Thread, inserted.id, &verdict).await?;
+    notifications::auto_watch(&mut tx, auth_user.id, inserted.id).await?;
+
+    // Shadowbanned users' threads and held threads shouldn't reach anyone else.
+    let is_visible = !inserted.shadowed && !inserted.is_held;
+
+    let notified = mentions::record_mentions(
+        &mut tx,
+        auth_user.id,
+        inserted.id,
+        None,
+        &req.content,
+        is_visible,
+    )
+    .await?;
+
     let thread = sqlx::query_as!(
         Thread,
         r#"
@@ -258,20 +504,267 @@ async fn create_thread(
                 title, 
                 content, 
                 a.created_at as "created_at: DateTime<Local>",
+                a.locked_at is not null as "is_locked!",
+                a.pinned_at is not null as "is_pinned!",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
                 false as "is_voted!",
-                0::bigint as "vote_count!" 
+                false as "is_saved!",
+                0::bigint as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.thread_id = a.id
+                        and mentions.comment_id is null
+                    order by mentions.id
+                ) as "mentions!"
             from threads a
             join users b on a.user_id = b.id
             where slug = $1
         "#,
         slug
     )
-    .fetch_one(&state.db)
+    .fetch_one(&mut tx)
     .await?;
 
+    permit.record(&state.rate_limiter)?;
+    tx.commit().await?;
+
+    metrics::increment_counter!("threads_created_total");
+
+    state.events.notify(notified);
+
+    if is_visible {
+        state
+            .events
+            .publish(Topic::Listing, Event::Thread(thread.clone()));
+    }
+
     Ok(Json(thread))
 }
 
+/// Edits a thread. Authors can edit their own threads unless they're locked;
+/// moderators can edit any thread, which is recorded in the moderation log.
+async fn edit_thread(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    ValidJson(req): ValidJson<ThreadEdit>,
+) -> Result<Json<Thread>> {
+    let title = req.title.as_deref().map(str::trim);
+    let content = req.content.as_deref();
+
+    let mut tx = state.db.begin().await?;
+
+    let existing = sqlx::query!(
+        r#"
+            select
+                id,
+                user_id,
+                shadowed,
+                locked_at is not null as "is_locked!"
+            from threads
+            where slug = $1
+                and removed_at is null
+            for update
+        "#,
+        slug
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let is_author = existing.user_id == auth_user.id;
+
+    if (!is_author || existing.is_locked) && auth_user.role(&state).await? < Role::Moderator {
+        return Err(Error::Forbidden);
+    }
+
+    let before = if is_author {
+        None
+    } else {
+        mod_log::thread_snapshot(&mut tx, existing.id).await?
+    };
+
+    let edited = sqlx::query!(
+        "
+            update threads
+            set title = coalesce($2, title),
+                content = coalesce($3, content),
+                edited_at = now()
+            where id = $1
+            returning title, content
+        ",
+        existing.id,
+        title,
+        content
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let verdict = state
+        .automod
+        .check(
+            &mut tx,
+            auth_user.id,
+            Post {
+                kind: PostKind::Thread,
+                title: Some(&edited.title),
+                content: &edited.content,
+            },
+        )
+        .await?;
+
+    automod::report(&mut tx, PostKind::Thread, existing.id, &verdict).await?;
+
+    let is_held = sqlx::query_scalar!(
+        r#"
+            update threads
+            set held_at = case when $2 then coalesce(held_at, now()) else held_at end
+            where id = $1
+            returning held_at is not null as "is_held!"
+        "#,
+        existing.id,
+        verdict.is_held()
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let is_visible = !existing.shadowed && !is_held;
+    let content = edited.content;
+
+    // Mentions added by a moderator's edit aren't the author's to send.
+    let notified = mentions::record_mentions(
+        &mut tx,
+        existing.user_id,
+        existing.id,
+        None,
+        &content,
+        is_author && is_visible,
+    )
+    .await?;
+
+    if let Some(before) = before {
+        let after = mod_log::thread_snapshot(&mut tx, existing.id)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        mod_log::record(
+            &mut tx,
+            ModLogEntry {
+                actor_user_id: auth_user.id,
+                action: ModAction::EditThread,
+                target_kind: "thread",
+                target_id: existing.id,
+                target_user_id: Some(before.user_id),
+                reason: req.reason.as_deref(),
+                before: Some(before.value),
+                after: Some(after.value),
+            },
+        )
+        .await?;
+    }
+
+    let thread = sqlx::query_as!(
+        Thread,
+        r#"
+            select
+                user_id as author_id,
+                username,
+                slug,
+                title,
+                content,
+                a.created_at as "created_at: DateTime<Local>",
+                a.locked_at is not null as "is_locked!",
+                a.pinned_at is not null as "is_pinned!",
+                a.edited_at as "edited_at: DateTime<Local>",
+                a.held_at is not null as "is_held!",
+                exists(
+                    select *
+                    from thread_votes
+                    where user_id = $2
+                    and thread_id = a.id
+                ) as "is_voted!",
+                exists(
+                    select *
+                    from thread_saves
+                    where user_id = $2
+                    and thread_id = a.id
+                ) as "is_saved!",
+                (select count(*) from thread_votes where thread_id = a.id) as "vote_count!",
+                array(
+                    select username
+                    from mentions
+                    join users on users.id = mentions.user_id
+                    where mentions.thread_id = a.id
+                        and mentions.comment_id is null
+                    order by mentions.id
+                ) as "mentions!"
+            from threads a
+            join users b on a.user_id = b.id
+            where a.id = $1
+        "#,
+        existing.id,
+        auth_user.id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    state.events.notify(notified);
+
+    if is_visible {
+        state
+            .events
+            .publish(Topic::Listing, Event::Thread(thread.clone()));
+        state
+            .events
+            .publish(Topic::Thread(slug), Event::Thread(thread.clone()));
+    }
+
+    Ok(Json(thread))
+}
+
+struct VotableThread {
+    id: i64,
+    /// Visible to everyone, rather than only to its author.
+    is_visible: bool,
+}
+
+/// A thread the user can see and so vote on.
+async fn votable_thread(state: &AppState, slug: &str, user_id: i64) -> Result<VotableThread> {
+    sqlx::query_as!(
+        VotableThread,
+        r#"
+            select
+                id,
+                not shadowed and held_at is null as "is_visible!"
+            from threads
+            where slug = $1
+                and removed_at is null
+                and ((not shadowed and held_at is null) or user_id = $2)
+        "#,
+        slug,
+        user_id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+/// Only call for visible threads, since the listing reaches everyone.
+fn publish_votes(state: &AppState, slug: String, count: &VoteCount) {
+    let event = Event::ThreadVotes {
+        slug: slug.clone(),
+        votes: count.clone(),
+    };
+
+    state.events.publish(Topic::Listing, event.clone());
+    state.events.publish(Topic::Thread(slug), event);
+}
+
 fn slugify(title: &str) -> String {
     let quotes = ['\'', '\"'];
 
@@ -285,3 +778,40 @@ fn slugify(title: &str) -> String {
         })
         .join("-")
 }
+
+impl Validate for NewThread {
+    fn check(&self, violations: &mut Violations) {
+        check_title(violations, &self.title);
+        check_content(violations, &self.content);
+    }
+}
+
+impl Validate for ThreadEdit {
+    fn check(&self, violations: &mut Violations) {
+        if let Some(title) = &self.title {
+            check_title(violations, title);
+        }
+
+        if let Some(content) = &self.content {
+            check_content(violations, content);
+        }
+    }
+}
+
+fn check_title(violations: &mut Violations, title: &str) {
+    violations
+        .field("title", title)
+        .not_blank()
+        .max_chars(MAX_TITLE_LENGTH)
+        .rule(
+            |title| !slugify(title).is_empty(),
+            "must contain a letter or number",
+        );
+}
+
+fn check_content(violations: &mut Violations, content: &str) {
+    violations
+        .field("content", content)
+        .not_blank()
+        .max_chars(MAX_CONTENT_LENGTH);
+}
@@ -1,10 +1,25 @@
-use super::{AppState, Error, Result, ResultExt};
+use super::{AppState, Error, Pagination, Result, ResultExt};
 use crate::auth::AuthUser;
+use crate::automod::PostKind;
+use crate::validate::{ValidJson, Validate, Violations};
 use argon2::{password_hash::SaltString, Argon2, PasswordHash};
-use axum::{extract::State, routing::post, Json, Router};
+use axum::{
+    extract::{Query, State},
+    routing::{get, post},
+    Json, Router,
+};
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 20;
+/// Names that clash with routes, like `/api/profiles/me`. The minimum length
+/// already rules out `me`, but not if it ever changes.
+const RESERVED_USERNAMES: &[&str] = &["me"];
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Longer passwords only make hashing slower.
+const MAX_PASSWORD_LENGTH: usize = 128;
+
 #[derive(Deserialize, Debug)]
 struct NewUser {
     username: String,
@@ -25,15 +40,34 @@ pub struct User {
     created_at: DateTime<Local>,
 }
 
+#[derive(Deserialize)]
+struct SavedFilter {
+    #[serde(rename = "type")]
+    kind: Option<PostKind>,
+}
+
+#[derive(Serialize)]
+struct SavedItem {
+    kind: String,
+    thread_slug: String,
+    thread_title: String,
+    comment_id: Option<i64>,
+    username: String,
+    content: String,
+    created_at: DateTime<Local>,
+    saved_at: DateTime<Local>,
+}
+
 pub(crate) fn router() -> Router<AppState> {
     Router::new()
         .route("/api/users", post(create_user).get(get_user))
         .route("/api/users/login", post(login_user))
+        .route("/api/users/saved", get(get_saved))
 }
 
 async fn login_user(
     State(state): State<AppState>,
-    Json(req): Json<LoginUser>,
+    ValidJson(req): ValidJson<LoginUser>,
 ) -> Result<Json<User>> {
     let user = sqlx::query!(
         "
@@ -48,9 +82,14 @@ async fn login_user(
     )
     .fetch_optional(&state.db)
     .await?
-    .ok_or_else(|| Error::unprocessable_entity([("username or password", "is incorrect")]))?;
+    .ok_or_else(|| Error::unprocessable_entity([("username or password", "is incorrect")]))
+    .map_err(login_failed)?;
+
+    verify_password(req.password, user.password_hash.clone())
+        .await
+        .map_err(login_failed)?;
 
-    verify_password(req.password, user.password_hash.clone()).await?;
+    metrics::increment_counter!("logins_total", "result" => "success");
 
     let score = sqlx::query_scalar!(
         r#"
@@ -73,7 +112,7 @@ async fn login_user(
 
 async fn create_user(
     State(state): State<AppState>,
-    Json(req): Json<NewUser>,
+    ValidJson(req): ValidJson<NewUser>,
 ) -> Result<Json<User>> {
     let password_hash = hash_password(req.password).await?;
 
@@ -133,7 +172,127 @@ async fn get_user(auth_user: AuthUser, State(state): State<AppState>) -> Result<
     }))
 }
 
-async fn hash_password(password: String) -> Result<String> {
+/// The threads and comments the caller saved, most recently saved first.
+async fn get_saved(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Query(filter): Query<SavedFilter>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<SavedItem>>> {
+    let items = sqlx::query_as!(
+        SavedItem,
+        r#"
+            select
+                kind as "kind!",
+                thread_slug as "thread_slug!",
+                thread_title as "thread_title!",
+                comment_id,
+                username as "username!",
+                content as "content!",
+                created_at as "created_at!: DateTime<Local>",
+                saved_at as "saved_at!: DateTime<Local>"
+            from (
+                select
+                    'thread' as kind,
+                    b.slug as thread_slug,
+                    b.title as thread_title,
+                    null::bigint as comment_id,
+                    c.username,
+                    b.content,
+                    b.created_at,
+                    a.created_at as saved_at
+                from thread_saves a
+                join threads b on a.thread_id = b.id
+                join users c on b.user_id = c.id
+                where a.user_id = $1
+                    and b.removed_at is null
+                    and ((not b.shadowed and b.held_at is null) or b.user_id = $1)
+                union all
+                select
+                    'comment' as kind,
+                    c.slug as thread_slug,
+                    c.title as thread_title,
+                    b.id as comment_id,
+                    d.username,
+                    b.content,
+                    b.created_at,
+                    a.created_at as saved_at
+                from comment_saves a
+                join comments b on a.comment_id = b.id
+                join threads c on b.thread_id = c.id
+                join users d on b.user_id = d.id
+                where a.user_id = $1
+                    and b.removed_at is null
+                    and c.removed_at is null
+                    and ((not b.shadowed and b.held_at is null) or b.user_id = $1)
+            ) saved
+            where $2::text is null or kind = $2
+            order by saved_at desc
+            limit $3
+            offset $4
+        "#,
+        auth_user.id,
+        filter.kind.map(|kind| kind.as_str()),
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(items))
+}
+
+/// Checks an account created outside the API against the same rules as a signup.
+pub(crate) fn validate_new_user(username: &str, password: &str) -> Result<()> {
+    NewUser {
+        username: username.to_string(),
+        password: password.to_string(),
+    }
+    .validate()
+}
+
+impl Validate for NewUser {
+    fn check(&self, violations: &mut Violations) {
+        violations
+            .field("username", &self.username)
+            .not_blank()
+            .min_chars(MIN_USERNAME_LENGTH)
+            .max_chars(MAX_USERNAME_LENGTH)
+            .charset(
+                |c| c.is_ascii_alphanumeric() || c == '-' || c == '_',
+                "can only contain letters, numbers, '-' and '_'",
+            )
+            .rule(
+                |username| {
+                    !RESERVED_USERNAMES
+                        .iter()
+                        .any(|reserved| reserved.eq_ignore_ascii_case(username))
+                },
+                "is reserved",
+            );
+
+        violations
+            .field("password", &self.password)
+            .not_blank()
+            .min_chars(MIN_PASSWORD_LENGTH)
+            .max_chars(MAX_PASSWORD_LENGTH);
+    }
+}
+
+impl Validate for LoginUser {
+    fn check(&self, violations: &mut Violations) {
+        violations.field("username", &self.username).not_blank();
+
+        violations.field("password", &self.password).not_blank();
+    }
+}
+
+fn login_failed(e: Error) -> Error {
+    metrics::increment_counter!("logins_total", "result" => "failure");
+    e
+}
+
+pub(crate) async fn hash_password(password: String) -> Result<String> {
     let salt = SaltString::generate(rand::thread_rng());
 
     Ok(
@@ -0,0 +1,76 @@
+use anyhow::Context;
+use axum::async_trait;
+use std::path::{Component, Path, PathBuf};
+use tower_http::services::ServeDir;
+
+/// Where uploaded files such as avatars are kept.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `bytes` under `key`, returning the public URL of the stored file.
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> anyhow::Result<String>;
+
+    /// Deletes a file stored by `put`, given the URL it returned. Deleting a
+    /// file that's already gone succeeds.
+    async fn delete(&self, url: &str) -> anyhow::Result<()>;
+}
+
+/// Stores files in a local directory, served by the API under `base_url`.
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, base_url: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            base_url: base_url.into(),
+        }
+    }
+
+    pub fn service(&self) -> ServeDir {
+        ServeDir::new(&self.root)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, _content_type: &str, bytes: Vec<u8>) -> anyhow::Result<String> {
+        let path = self.root.join(key);
+
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        tokio::fs::write(&path, bytes)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(format!("{}/{}", self.base_url.trim_end_matches('/'), key))
+    }
+
+    async fn delete(&self, url: &str) -> anyhow::Result<()> {
+        let key = url
+            .strip_prefix(self.base_url.trim_end_matches('/'))
+            .and_then(|key| key.strip_prefix('/'))
+            .with_context(|| format!("{} isn't a stored file", url))?;
+
+        if !Path::new(key)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("{} isn't a stored file", url);
+        }
+
+        let path = self.root.join(key);
+
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to delete {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
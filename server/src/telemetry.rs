@@ -0,0 +1,166 @@
+use axum::{
+    extract::{MatchedPath, State},
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+    routing::get,
+    Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+use tower_http::request_id::RequestId;
+use tracing::{field, Span};
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+/// Upper bounds, in seconds, of the request latency histogram's buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// How long a scrape waits to sample the time it takes to get a database connection.
+const ACQUIRE_SAMPLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Installs the global logger, which also collects `log` records from
+/// dependencies like sqlx. Logs are JSON unless `LOG_FORMAT` is `text`, and
+/// filtered by `RUST_LOG`, defaulting to `info`.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let text = dotenvy::var("LOG_FORMAT").is_ok_and(|format| format == "text");
+
+    let registry = tracing_subscriber::registry().with(filter);
+
+    if text {
+        registry.with(tracing_subscriber::fmt::layer()).init();
+    } else {
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(false),
+            )
+            .init();
+    }
+}
+
+/// The id of the request being handled, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Makes the request's `X-Request-Id` available to [`request_id`] while it's handled.
+pub(crate) async fn scope_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    REQUEST_ID.scope(id, next.run(request)).await
+}
+
+/// The span every request is handled in. Its status and latency are filled
+/// in by [`on_response`].
+pub(crate) fn make_span<B>(request: &Request<B>) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .unwrap_or_else(|| request.uri().path());
+
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.header_value())
+        .and_then(|id: &HeaderValue| id.to_str().ok())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        route,
+        request_id,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    )
+}
+
+pub(crate) fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", latency.as_millis() as u64);
+
+    tracing::info!("finished request");
+}
+
+/// Installs the global metrics recorder. Until it's installed, recording
+/// metrics does nothing.
+pub fn install_metrics() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("http_request_duration_seconds".to_string()),
+            LATENCY_BUCKETS,
+        )
+        .expect("latency buckets can't be empty")
+        .install_recorder()
+        .expect("could not install metrics recorder")
+}
+
+/// Counts requests and their latency by method, route and status.
+pub(crate) async fn record_metrics<B>(request: Request<B>, next: Next<B>) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+
+    metrics::increment_counter!("http_requests_total", &labels);
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        &labels
+    );
+
+    response
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    handle: PrometheusHandle,
+    db: PgPool,
+}
+
+/// Serves `/metrics` in the Prometheus text format. Meant to be bound to an
+/// internal address, apart from the API.
+pub fn metrics_router(handle: PrometheusHandle, db: PgPool) -> Router {
+    Router::new()
+        .route("/metrics", get(render_metrics))
+        .with_state(MetricsState { handle, db })
+}
+
+async fn render_metrics(State(state): State<MetricsState>) -> String {
+    metrics::gauge!("db_pool_connections", state.db.size() as f64);
+    metrics::gauge!("db_pool_idle_connections", state.db.num_idle() as f64);
+
+    // sqlx doesn't report how long queries wait for a connection, so sample it.
+    let start = Instant::now();
+    let _ = tokio::time::timeout(ACQUIRE_SAMPLE_TIMEOUT, state.db.acquire()).await;
+    metrics::gauge!("db_pool_acquire_seconds", start.elapsed().as_secs_f64());
+
+    state.handle.render()
+}
@@ -0,0 +1,232 @@
+use crate::error::Error;
+use axum::{
+    async_trait,
+    extract::FromRequest,
+    http::Request,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::borrow::Cow;
+
+/// A request body with rules on its fields.
+pub trait Validate {
+    /// Checks every rule, recording each one that's broken.
+    fn check(&self, violations: &mut Violations);
+
+    /// Fails with every broken rule at once, if any are.
+    fn validate(&self) -> Result<(), Error> {
+        let mut violations = Violations::default();
+        self.check(&mut violations);
+
+        if violations.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::unprocessable_entity(violations.errors))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Violations {
+    errors: Vec<(&'static str, Cow<'static, str>)>,
+}
+
+impl Violations {
+    /// Starts checking the rules for a field.
+    pub fn field<'a>(&'a mut self, name: &'static str, value: &'a str) -> Field<'a> {
+        Field {
+            violations: self,
+            name,
+            value,
+            is_blank: false,
+        }
+    }
+
+    /// Records a broken rule that isn't about a single string, like one on a
+    /// number or a list.
+    pub fn add(&mut self, name: &'static str, message: impl Into<Cow<'static, str>>) {
+        self.errors.push((name, message.into()));
+    }
+}
+
+/// The rules for one field, checked in the order they're chained. Once a
+/// field is found blank, the rules after aren't checked.
+pub struct Field<'a> {
+    violations: &'a mut Violations,
+    name: &'static str,
+    value: &'a str,
+    is_blank: bool,
+}
+
+impl Field<'_> {
+    /// Not empty or only whitespace.
+    pub fn not_blank(mut self) -> Self {
+        if !self.is_blank && self.value.trim().is_empty() {
+            self = self.violate("can't be empty");
+            self.is_blank = true;
+        }
+
+        self
+    }
+
+    pub fn min_chars(self, min: usize) -> Self {
+        self.rule(
+            |value| value.chars().count() >= min,
+            format!("must be at least {} characters", min),
+        )
+    }
+
+    pub fn max_chars(self, max: usize) -> Self {
+        self.rule(
+            |value| value.chars().count() <= max,
+            format!("must be at most {} characters", max),
+        )
+    }
+
+    /// Every character is one of the allowed ones, which the message describes.
+    pub fn charset(self, is_allowed: impl Fn(char) -> bool, message: &'static str) -> Self {
+        self.rule(|value| value.chars().all(is_allowed), message)
+    }
+
+    pub fn rule(
+        self,
+        is_valid: impl FnOnce(&str) -> bool,
+        message: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        if self.is_blank || is_valid(self.value) {
+            self
+        } else {
+            self.violate(message)
+        }
+    }
+
+    fn violate(self, message: impl Into<Cow<'static, str>>) -> Self {
+        self.violations.add(self.name, message);
+        self
+    }
+}
+
+/// A JSON request body that follows its rules. Otherwise the request is
+/// rejected with all the rules it broke.
+pub struct ValidJson<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for ValidJson<T>
+where
+    T: Validate,
+    Json<T>: FromRequest<S, B>,
+    <Json<T> as FromRequest<S, B>>::Rejection: IntoResponse,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        value.validate().map_err(IntoResponse::into_response)?;
+
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Signup {
+        username: String,
+        bio: String,
+    }
+
+    impl Validate for Signup {
+        fn check(&self, violations: &mut Violations) {
+            violations
+                .field("username", &self.username)
+                .not_blank()
+                .min_chars(3)
+                .max_chars(8)
+                .charset(
+                    |c| c.is_ascii_alphanumeric(),
+                    "can only contain letters and numbers",
+                );
+
+            violations.field("bio", &self.bio).max_chars(5);
+        }
+    }
+
+    fn violations(username: &str, bio: &str) -> Vec<(&'static str, String)> {
+        let signup = Signup {
+            username: username.to_string(),
+            bio: bio.to_string(),
+        };
+        let mut violations = Violations::default();
+        signup.check(&mut violations);
+
+        violations
+            .errors
+            .into_iter()
+            .map(|(name, message)| (name, message.into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn passes_valid_values() {
+        assert!(violations("alice", "hi").is_empty());
+    }
+
+    #[test]
+    fn skips_the_remaining_rules_of_a_blank_field() {
+        assert_eq!(
+            violations("  ", ""),
+            [("username", "can't be empty".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_every_broken_rule_at_once() {
+        assert_eq!(
+            violations("a!", "too long"),
+            [
+                ("username", "must be at least 3 characters".to_string()),
+                (
+                    "username",
+                    "can only contain letters and numbers".to_string()
+                ),
+                ("bio", "must be at most 5 characters".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_characters_rather_than_bytes() {
+        assert!(violations("alice", "ååååå").is_empty());
+        assert_eq!(
+            violations("alice", "åååååå"),
+            [("bio", "must be at most 5 characters".to_string())]
+        );
+    }
+
+    #[test]
+    fn records_rules_on_other_values() {
+        let mut violations = Violations::default();
+        violations.add("links", "must have at most 5 links");
+
+        assert_eq!(violations.errors.len(), 1);
+    }
+
+    #[test]
+    fn fails_validation_with_an_unprocessable_entity() {
+        let signup = Signup {
+            username: String::new(),
+            bio: String::new(),
+        };
+
+        assert!(matches!(
+            signup.validate(),
+            Err(Error::UnprocessableEntity { errors }) if errors["username"] == ["can't be empty"]
+        ));
+    }
+}